@@ -1,31 +1,13 @@
 #import bevy_pbr::mesh_view_bindings
 #import bevy_pbr::mesh_bindings
 #import cel::utils
+#import cel::material
 
 #import bevy_pbr::mesh_functions
+#import bevy_pbr::prepass_utils
+#import bevy_pbr::shadows
 
 
-struct Direction {
-    forward: vec3<f32>,
-    right: vec3<f32>,
-}
-
-struct ShadowRamp {
-    width: f32,
-    day_mult_colors: array<vec4<f32>, 5>,
-    night_mult_colors: array<vec4<f32>, 5>,
-    transition_range1: f32,
-    transition_range2: f32,
-    transition_range3: f32,
-    transition_range4: f32,
-    transition_range5: f32,
-    transition_softness1: f32,
-    transition_softness2: f32,
-    transition_softness3: f32,
-    transition_softness4: f32,
-    transition_softness5: f32,
-}
-
 struct ShadowRampValue {
     day_mult_color: vec4<f32>,
     night_mult_color: vec4<f32>,
@@ -69,107 +51,11 @@ fn get_shadow_ramp_value(ramp: ShadowRamp, index: i32) -> ShadowRampValue {
     return value;
 }
 
-struct GlobalSpecular {
-    shininess1: f32,
-    shininess2: f32,
-    shininess3: f32,
-    shininess4: f32,
-    shininess5: f32,
-    specular_multi1: f32,
-    specular_multi2: f32,
-    specular_multi3: f32,
-    specular_multi4: f32,
-    specular_multi5: f32,
-}
-
 struct GlobalSpecularValue {
     shininess: f32,
     specular_multi: f32,
 }
 
-struct MaterialProperties {
-    head_direction: Direction,
-    shadow_ramp_values: ShadowRamp,
-    global_specular: GlobalSpecular,
-
-    use_materials: vec4<f32>, //1 1 1 1 1
-    metal_map_light_color: vec4<f32>,
-    metal_map_dark_color: vec4<f32>,
-    metal_map_shadow_multi_color: vec4<f32>,
-    metal_map_sharp_layer_color: vec4<f32>,
-    metal_map_specular_color: vec4<f32>,
-    specular_color: vec4<f32>,
-    hit_color: vec4<f32>,
-
-    day_night_cycle: f32,
-
-    use_shadow_ramp_texture: f32,
-
-    light_area: f32, //0.55
-    flip_light_map: f32, //0
-    face_map_softness: f32, //0.1
-
-    use_ligth_map_color_ao: f32, //1
-    use_vertex_color_ao: f32, //1
-
-    normal_map_scale: f32, //0.2
-
-    use_normal_map: f32, //1
-
-    use_back_space_uv: f32, //1
-
-    use_metal_map: f32,
-    metal_map_tile_scale: f32,
-    metal_map_brightness: f32,
-
-    metal_map_shininess: f32,
-    metal_map_sharp_layer_offset: f32,
-    
-    metal_map_specular_atten_in_shadow: f32,
-    metal_map_specular_scale: f32,
-
-    use_fresnel: f32,
-    hit_color_fresnel_power: f32,
-    hit_color_scaler: f32,
-
-    rim_light_type: f32,
-    rim_light_intensity: f32,
-    rim_light_thickness: f32,
-}
-
-@group(1) @binding(0)
-var diffuse_tex: texture_2d<f32>;
-@group(1) @binding(1)
-var diffuse_sampler: sampler;
-
-@group(1) @binding(2)
-var face_light_map_tex: texture_2d<f32>;
-@group(1) @binding(3)
-var face_light_map_sampler: sampler;
-
-@group(1) @binding(4)
-var light_map_tex: texture_2d<f32>;
-@group(1) @binding(5)
-var light_map_sampler: sampler;
-
-@group(1) @binding(6)
-var shadow_ramp_tex: texture_2d<f32>;
-@group(1) @binding(7)
-var shadow_ramp_sampler: sampler;
-
-@group(1) @binding(8)
-var normal_map_tex: texture_2d<f32>;
-@group(1) @binding(9)
-var normal_map_sampler: sampler;
-
-@group(1) @binding(10)
-var metal_map_tex: texture_2d<f32>;
-@group(1) @binding(11)
-var metal_map_sampler: sampler;
-
-@group(1) @binding(12)
-var<uniform> properties: MaterialProperties;
-
 fn get_specular_value(material_id: i32) -> GlobalSpecularValue {
     let gs = properties.global_specular;
     var value = GlobalSpecularValue(gs.shininess1, gs.specular_multi1);
@@ -210,37 +96,181 @@ fn get_specular_value(material_id: i32) -> GlobalSpecularValue {
 // _MTShadowMultiColor
 
 
+// depth written by the GBUFFER_DEPTH pass is divided by this, in meters, 16 bits give steps of 0.15 mm
+let MAX_LINEAR_DEPTH: f32 = 10.0;
+
 fn saturate(value: f32) -> f32 {
     return clamp(value,0.0,1.0);
 }
 
+fn saturate2(value: vec2<f32>) -> vec2<f32> {
+    return clamp(value, vec2<f32>(0.0), vec2<f32>(1.0));
+}
+
 fn saturate4(value: vec4<f32>) -> vec4<f32> {
     return clamp(value, vec4<f32>(0.0), vec4<f32>(1.0));
 }
 
+// Data passes are rendered into a linear target, the written bytes are the values themselves
+fn gbuffer_output(value: vec3<f32>) -> vec4<f32> {
+    return vec4<f32>(value, 1.0);
+}
+
+// Splits a 0-1 value over the bytes of two channels, high byte first
+fn pack_16(value: f32) -> vec2<f32> {
+    let scaled = round(saturate(value) * 65535.0);
+    let high = floor(scaled / 256.0);
+    return vec2<f32>(high, scaled - high * 256.0) / 255.0;
+}
+
+// Packs 12 bits of each coordinate into three bytes: the 8 high bits of u, the 4 low bits of u
+// with the 4 high bits of v, then the 8 low bits of v
+fn pack_uv(uv: vec2<f32>) -> vec3<f32> {
+    let scaled = round(saturate2(uv) * 4095.0);
+    let u_high = floor(scaled.x / 16.0);
+    let v_high = floor(scaled.y / 256.0);
+    return vec3<f32>(
+        u_high,
+        (scaled.x - u_high * 16.0) * 16.0 + v_high,
+        scaled.y - v_high * 256.0
+    ) / 255.0;
+}
+
 fn calculate_view(
     world_position: vec4<f32>,
 ) -> vec3<f32> {
     return normalize(view.world_position.xyz - world_position.xyz);
 }
 
+fn light_direction(light: vec4<f32>, world_position: vec4<f32>) -> vec3<f32> {
+    if light.w > 0.0 {
+        return normalize(light.xyz - world_position.xyz);
+    }
+    return normalize(light.xyz);
+}
+
+// 1 where the main light reaches the fragment, 0 in the shadow of another mesh.
+// Only the main light casts shadows, it is the light with the shadows flag.
+fn cast_shadow(world_position: vec4<f32>, world_normal: vec3<f32>) -> f32 {
+#ifdef CEL_SHADOWS
+    var shadow = 1.0;
+    if properties.lights.main_position.w > 0.0 {
+        // bevy sorts the shadow casting point lights first
+        if (point_lights.data[0].flags & POINT_LIGHT_FLAGS_SHADOWS_ENABLED_BIT) != 0u {
+            shadow = fetch_point_shadow(0u, world_position, world_normal);
+        }
+    } else {
+        let view_z = dot(vec4<f32>(
+            view.inverse_view[0].z,
+            view.inverse_view[1].z,
+            view.inverse_view[2].z,
+            view.inverse_view[3].z
+        ), world_position);
+        // directional lights keep the order of the query, the fill light can come first
+        for (var i: u32 = 0u; i < lights.n_directional_lights; i = i + 1u) {
+            if (lights.directional_lights[i].flags & DIRECTIONAL_LIGHT_FLAGS_SHADOWS_ENABLED_BIT) != 0u {
+                shadow = fetch_directional_shadow(i, world_position, world_normal, view_z);
+                break;
+            }
+        }
+    }
+    // hard edge like the lightmap shadows, the ramp adds the softness
+    return step(0.5, shadow);
+#else
+    return 1.0;
+#endif
+}
+
+// emission strength written as 1 by the GBUFFER_EMISSION pass
+let MAX_EMISSION_STRENGTH: f32 = 4.0;
+
+fn get_emission_scaler(material_id: i32) -> f32 {
+    var scaler = properties.emission_scaler1;
+    if material_id == 2 {
+        scaler = properties.emission_scaler2;
+    }
+    if material_id == 3 {
+        scaler = properties.emission_scaler3;
+    }
+    if material_id == 4 {
+        scaler = properties.emission_scaler4;
+    }
+    if material_id == 5 {
+        scaler = properties.emission_scaler5;
+    }
+    return mix(properties.emission_scaler, scaler, properties.emission_strength_lerp);
+}
+
+// Emission strength of the fragment, the diffuse alpha masks the glowing parts when _MainTexAlphaUse is 1
+fn emission_strength(uv: vec2<f32>) -> f32 {
+    if properties.use_emission == 0.0 {
+        return 0.0;
+    }
+    let mask = textureSample(diffuse_tex, diffuse_sampler, transform_uv(uv, properties.main_tex_st)).a;
+#ifdef FACE
+    let material_id = 1;
+#else
+    let id_mask = textureSample(light_map_tex, light_map_sampler, transform_uv(uv, properties.light_map_tex_st)).w;
+    let material_id = i32(id_mask * 5.0);
+    let material_id = select(1, material_id, properties.use_materials[material_id] > 0.0);
+#endif
+    return mask * get_emission_scaler(material_id);
+}
+
+// rim light offset in screen heights for a surface 1 meter away, multiplied by _RimLightThickness
+let RIM_LIGHT_WIDTH: f32 = 0.004;
+// depth difference in meters at which the rim light reaches full intensity
+let RIM_LIGHT_DEPTH_THRESHOLD: f32 = 0.05;
+
+#ifdef DEPTH_PREPASS
+fn prepass_linear_depth(frag_coord: vec2<f32>) -> f32 {
+    let size = view.viewport.zw;
+    let coord = clamp(frag_coord, vec2<f32>(0.0), size - 1.0);
+    // reversed infinite perspective projection: depth = near / view depth
+    return view.projection[3][2] / max(prepass_depth(vec4<f32>(coord, 0.0, 0.0), 0u), 0.000001);
+}
+#endif
+
+// Screen space rim light: the fragment is on a silhouette when the depth prepass is much
+// farther away a few pixels outwards along the view space normal.
+fn rim_light(frag_coord: vec4<f32>, world_position: vec4<f32>, world_normal: vec3<f32>) -> f32 {
+#ifdef DEPTH_PREPASS
+    let view_normal = normalize((view.inverse_view * vec4<f32>(world_normal, 0.0)).xyz);
+    let depth = -(view.inverse_view * world_position).z;
+    // screen y points down
+    let offset = view_normal.xy * vec2<f32>(1.0, -1.0)
+        * properties.rim_light_thickness * RIM_LIGHT_WIDTH * view.viewport.w / max(depth, 0.01);
+    let depth_difference = prepass_linear_depth(frag_coord.xy + offset) - depth;
+    return smoothstep(0.0, RIM_LIGHT_DEPTH_THRESHOLD, depth_difference) * properties.rim_light_intensity;
+#else
+    return 0.0;
+#endif
+}
+
+// the blend is kept below 1, a full rim would divide by zero and turn the silhouette white
+fn color_dodge(blend: vec3<f32>, base: vec3<f32>) -> vec3<f32> {
+    return base / (vec3<f32>(1.0) - min(blend, vec3<f32>(0.99)));
+}
+
 fn texel_size(texture: texture_2d<f32>) -> vec4<f32> {
     let size = vec2<f32>(textureDimensions(texture));
     return vec4<f32>(1.0/size.x, 1.0/size.y, size);
 }
 
+// V coordinate of the shadow ramp row of the material, the night rows are in the lower half
+fn shadow_ramp_row(material_id: i32, night: bool) -> f32 {
+    return ((5.0 - f32(material_id)) * 0.1) + 0.05 + select(0.0, 0.5, night);
+}
+
 fn shadow_ramp_face(material_id: i32, factor: f32) -> vec4<f32> {
     if properties.use_shadow_ramp_texture > 0.0 {
-        let day = textureSample(shadow_ramp_tex, shadow_ramp_sampler, vec2<f32>(factor, (((6.0 - f32(material_id)) - 1.0) * 0.1) + 0.05));
-        let night = textureSample(shadow_ramp_tex, shadow_ramp_sampler, vec2<f32>(factor, (((6.0 - f32(material_id)) - 1.0) * 0.1) + 0.05 + 0.5));
+        let day = textureSample(shadow_ramp_tex, shadow_ramp_sampler, transform_uv(vec2<f32>(factor, shadow_ramp_row(material_id, false)), properties.shadow_ramp_tex_st));
+        let night = textureSample(shadow_ramp_tex, shadow_ramp_sampler, transform_uv(vec2<f32>(factor, shadow_ramp_row(material_id, true)), properties.shadow_ramp_tex_st));
 
         return mix(day, night, properties.day_night_cycle);
     } else {
-        return mix(
-            properties.shadow_ramp_values.day_mult_colors[material_id], 
-            properties.shadow_ramp_values.night_mult_colors[material_id], 
-            properties.day_night_cycle
-        );
+        let value = get_shadow_ramp_value(properties.shadow_ramp_values, material_id);
+        return mix(value.day_mult_color, value.night_mult_color, properties.day_night_cycle);
     }
 }
 
@@ -263,8 +293,8 @@ fn shadow_ramp(material_id: i32, factor: f32, occlusion: f32, shadow_ramp_multip
         let factor = 1.0 - ((properties.light_area - factor) / properties.light_area) / width;
         let factor = clamp(factor - 0.5, 0.001, 0.999);
 
-        let day = textureSample(shadow_ramp_tex, shadow_ramp_sampler, vec2<f32>(factor, (((6.0 - f32(4))) * 0.1) + 0.05));
-        let night = textureSample(shadow_ramp_tex, shadow_ramp_sampler, vec2<f32>(factor, (((6.0 - f32(material_id)) - 1.0) * 0.1) + 0.05 + 0.5));
+        let day = textureSample(shadow_ramp_tex, shadow_ramp_sampler, transform_uv(vec2<f32>(factor, shadow_ramp_row(material_id, false)), properties.shadow_ramp_tex_st));
+        let night = textureSample(shadow_ramp_tex, shadow_ramp_sampler, transform_uv(vec2<f32>(factor, shadow_ramp_row(material_id, true)), properties.shadow_ramp_tex_st));
 
         let shadow = mix(day, night, properties.day_night_cycle);
 
@@ -300,18 +330,18 @@ fn shadow_ramp(material_id: i32, factor: f32, occlusion: f32, shadow_ramp_multip
 
         let shadow_day = factor * first_color;
         let shadow_night = factor * cool_color;
-        let shadow = mix(shadow_day, shadow_day, properties.day_night_cycle);
+        let shadow = mix(shadow_day, shadow_night, properties.day_night_cycle);
 
         return mix(vec4(1.0), shadow, factor);
     }
 }
 
-fn face_color(uv: vec2<f32>, vertex_color: vec4<f32>, world_normal: vec3<f32>, light_dir: vec3<f32>) -> vec4<f32> {
-    let color = textureBicubic(diffuse_tex, diffuse_sampler, uv);
+fn face_color(uv: vec2<f32>, vertex_color: vec4<f32>, world_position: vec4<f32>, world_normal: vec3<f32>, light_dir: vec3<f32>) -> vec4<f32> {
+    let color = textureBicubic(diffuse_tex, diffuse_sampler, transform_uv(uv, properties.main_tex_st));
 
-    let light_map = textureBicubic(face_light_map_tex, face_light_map_sampler, uv);
-    let light_map_mirrored = textureBicubic(face_light_map_tex, face_light_map_sampler, vec2(1.0 - uv.x, uv.y));
-    let face_map = textureBicubic(light_map_tex, light_map_sampler, uv);
+    let light_map = textureBicubic(face_light_map_tex, face_light_map_sampler, transform_uv(uv, properties.face_map_tex_st));
+    let light_map_mirrored = textureBicubic(face_light_map_tex, face_light_map_sampler, transform_uv(vec2(1.0 - uv.x, uv.y), properties.face_map_tex_st));
+    let face_map = textureBicubic(light_map_tex, light_map_sampler, transform_uv(uv, properties.light_map_tex_st));
 
     let light_2d = normalize(light_dir.xz);
     let forward_light = dot(light_2d, properties.head_direction.forward.xz);
@@ -330,6 +360,8 @@ fn face_color(uv: vec2<f32>, vertex_color: vec4<f32>, world_normal: vec3<f32>, l
 
     let face_light = smoothstep(shadow_range - properties.face_map_softness, shadow_range + properties.face_map_softness, light_map_dir.w);
     let face_light = face_light + face_map.w * (1.0 - forward_light);
+    // e.g. the hair shadowing the forehead
+    let face_light = face_light * cast_shadow(world_position, world_normal);
     let lit_factor = 1.0 - face_light;
 
     let light = dot(light_dir, world_normal);
@@ -340,7 +372,7 @@ fn face_color(uv: vec2<f32>, vertex_color: vec4<f32>, world_normal: vec3<f32>, l
     //let shadow = shadow_ramp(1, face_light, occlusion/2.0, vertex_color.g);
     let shadow = mix(shadow, vec4<f32>(1.0), face_light);
 
-    return color * shadow;//color * shadow;//vec4<f32>(face_light);//
+    return color * shadow * vec4<f32>(properties.lights.main_color.rgb, 1.0);//color * shadow;//vec4<f32>(face_light);//
 }
 
 fn standart_cel_color(
@@ -356,9 +388,9 @@ fn standart_cel_color(
 ) -> vec4<f32> {
     let uv = select(normal_map_uv, uv, is_front || !(properties.use_back_space_uv > 0.0));
 
-    let color = textureBicubic(diffuse_tex, diffuse_sampler, uv);
+    let color = textureBicubic(diffuse_tex, diffuse_sampler, transform_uv(uv, properties.main_tex_st));
 
-    let light_map = textureBicubic(light_map_tex, light_map_sampler, uv);
+    let light_map = textureBicubic(light_map_tex, light_map_sampler, transform_uv(uv, properties.light_map_tex_st));
 
     let id_mask = light_map.w;
 
@@ -375,7 +407,7 @@ fn standart_cel_color(
 
         // normal = normalize(mat3x3(tangent, bitangent, world_normal) * mapped_normal.xyz);
 
-        let bump = textureBicubic(normal_map_tex, normal_map_sampler, uv);
+        let bump = textureBicubic(normal_map_tex, normal_map_sampler, transform_uv(uv, properties.normal_map_tex_st));
         var new_normal = vec3<f32>();
         var modified_normal = vec4<f32>();
         modified_normal = bump;
@@ -434,6 +466,8 @@ fn standart_cel_color(
     let light = dot(normal, light_dir);
     // remap from { -1, 1 } to { 0, 1 }
     let light = light * 0.5 + 0.5;
+    // cast shadows push the fragment into the shadow side of the ramp
+    let light = light * cast_shadow(world_position, world_normal);
 
     //specular
    // let view_direction = calculate_view(world_position);
@@ -471,7 +505,7 @@ fn standart_cel_color(
     let matcapuvs = vec2<f32>(matcapuvs.x * properties.metal_map_tile_scale, matcapuvs.y) * 0.5 + vec2<f32>(0.5, 0.5);
 
     // sample matcap texture with newly created UVs
-    let metal = textureSample(metal_map_tex, metal_map_sampler, matcapuvs);
+    let metal = textureSample(metal_map_tex, metal_map_sampler, transform_uv(matcapuvs, properties.metal_map_tex_st));
     
     // prevent metallic matcap from glowing
     let metal = saturate4(metal * properties.metal_map_brightness);
@@ -507,7 +541,8 @@ fn standart_cel_color(
         color = color * shadow.xyz + specular.xyz;
     }
 
-    return vec4<f32>(color, 1.0);// * light;
+    // tints the lit and the shadowed side alike, the ramp keeps its bands
+    return vec4<f32>(color * properties.lights.main_color.rgb, 1.0);// * light;
 }
 
 
@@ -629,12 +664,60 @@ fn fragment(
 #endif
 ) -> @location(0) vec4<f32> {
 
-    let light = point_lights.data[0].position_radius.xyz;
-    let light_dir = light - world_position.xyz;
-    let light_dir = normalize(light_dir);
+    let alpha = textureSample(diffuse_tex, diffuse_sampler, transform_uv(uv, properties.main_tex_st)).a;
+    if alpha_clipped(alpha, frag_coord) {
+        discard;
+    }
+#ifdef ALPHA_BLEND
+    let alpha = alpha;
+#else
+    let alpha = 1.0;
+#endif
+
+    // the main light drives the ramp
+    let light_dir = light_direction(properties.lights.main_position, world_position);
+    // the specular of the body shading is computed from a light position
+    let light = world_position.xyz + light_dir;
+
+#ifdef GBUFFER_WORLD_NORMAL
+    return gbuffer_output(normalize(world_normal) * 0.5 + 0.5);
+#endif
+#ifdef GBUFFER_VIEW_NORMAL
+    let view_normal = (view.inverse_view * vec4<f32>(world_normal, 0.0)).xyz;
+    return gbuffer_output(normalize(view_normal) * 0.5 + 0.5);
+#endif
+#ifdef GBUFFER_DEPTH
+    let view_position = view.inverse_view * world_position;
+    return gbuffer_output(vec3<f32>(pack_16(-view_position.z / MAX_LINEAR_DEPTH), 0.0));
+#endif
+#ifdef GBUFFER_MATERIAL_ID
+#ifdef FACE
+    return gbuffer_output(vec3<f32>(0.0));
+#else
+    let id_mask = textureSample(light_map_tex, light_map_sampler, transform_uv(uv, properties.light_map_tex_st)).w;
+    return gbuffer_output(vec3<f32>(f32(i32(id_mask * 5.0)) / 5.0));
+#endif
+#endif
+#ifdef GBUFFER_FACE_MASK
+#ifdef FACE
+    return gbuffer_output(vec3<f32>(1.0));
+#else
+    return gbuffer_output(vec3<f32>(0.0));
+#endif
+#endif
+#ifdef GBUFFER_LIGHT_TERM
+    return gbuffer_output(vec3<f32>(dot(normalize(world_normal), light_dir) * 0.5 + 0.5));
+#endif
+#ifdef GBUFFER_EMISSION
+    return gbuffer_output(vec3<f32>(saturate(emission_strength(uv) / MAX_EMISSION_STRENGTH)));
+#endif
+#ifdef GBUFFER_UV
+    return gbuffer_output(pack_uv(fract(uv)));
+#endif
 
 #ifdef SIMPLE
-    return textureSample(diffuse_tex, diffuse_sampler, uv) * max(0.2, dot(light_dir, world_normal));
+    let diffuse = textureSample(diffuse_tex, diffuse_sampler, transform_uv(uv, properties.main_tex_st));
+    return vec4<f32>(diffuse.rgb * max(0.2, dot(light_dir, world_normal)), alpha);
 #else //SIMPLE
 
   //  let light_smooth = 0.1;
@@ -646,7 +729,7 @@ fn fragment(
     
 
 #ifdef FACE
-    let color = face_color(uv, color, world_normal, light_dir);
+    let color = face_color(uv, color, world_position, world_normal, light_dir);
 #else //FACE
 
     // let world_tangent = vec4<f32>();
@@ -686,20 +769,35 @@ fn fragment(
 
     let fresnel = properties.hit_color.xyz * vec3<f32>(frensel_factor * properties.hit_color_scaler);
 
-    //  half rimLight = calculateRimLight(i.normal, i.screenPos, _RimLightIntensity, 
-    //                                   _RimLightThickness, 1.0 - litFactor);
-
-    // // rim light mustn't appear in backfaces
-    // rimLight *= frontFacing;
+    // only on the lit side, the shadow side keeps its ramp color
+    let half_lambert = dot(normalize(world_normal), light_dir) * 0.5 + 0.5;
+    let lit_factor = smoothstep(properties.light_area - 0.05, properties.light_area + 0.05, half_lambert);
+    // rim light mustn't appear in backfaces
+    let rim = rim_light(frag_coord, world_position, world_normal) * lit_factor * f32(is_front);
 
     var color = color.xyz;
 
     color += f32(properties.use_fresnel != 0.0) * fresnel;
 
-    // apply rim light
-    //color.xyz = (_RimLightType != 0) ? ColorDodge(rimLight, finalColor.xyz) : finalColor.xyz + rimLight;
+    let diffuse = textureSample(diffuse_tex, diffuse_sampler, transform_uv(uv, properties.main_tex_st)).rgb;
+
+    // the fill light does not change the cel bands, it only brightens the side it faces
+    if properties.lights.fill_color.a > 0.0 {
+        let fill_dir = light_direction(properties.lights.fill_position, world_position);
+        let fill = saturate(dot(normalize(world_normal), fill_dir));
+        color += diffuse * properties.lights.fill_color.rgb * fill * f32(is_front);
+    }
+
+    // glowing parts keep their diffuse color in the shadow
+    color += diffuse * emission_strength(uv);
+
+    if properties.rim_light_type != 0.0 {
+        color = color_dodge(vec3<f32>(rim), color);
+    } else {
+        color += vec3<f32>(rim);
+    }
 
-    return vec4<f32>(color, 1.0);
+    return vec4<f32>(color, alpha);
     // #ifndef FACE
     // return vec4<f32>(normal_map_uv.x, normal_map_uv.y, 1.0, 1.0);
     // #else
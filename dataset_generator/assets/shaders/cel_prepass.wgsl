@@ -0,0 +1,36 @@
+#import cel::material
+
+// Fragment stage of the prepass and the shadow maps for cut out materials, so the holes of the color pass
+// do not cast shadows or rim lights. The vertex stage is bevy's prepass shader.
+
+struct FragmentInput {
+    @builtin(position) frag_coord: vec4<f32>,
+#ifdef VERTEX_UVS
+    @location(0) uv: vec2<f32>,
+#endif
+#ifdef NORMAL_PREPASS
+    @location(1) world_normal: vec3<f32>,
+#endif
+};
+
+fn clip(in: FragmentInput) {
+#ifdef VERTEX_UVS
+    let alpha = textureSample(diffuse_tex, diffuse_sampler, transform_uv(in.uv, properties.main_tex_st)).a;
+    if alpha_clipped(alpha, in.frag_coord) {
+        discard;
+    }
+#endif
+}
+
+#ifdef NORMAL_PREPASS
+@fragment
+fn fragment(in: FragmentInput) -> @location(0) vec4<f32> {
+    clip(in);
+    return vec4<f32>(normalize(in.world_normal) * 0.5 + 0.5, 1.0);
+}
+#else
+@fragment
+fn fragment(in: FragmentInput) {
+    clip(in);
+}
+#endif
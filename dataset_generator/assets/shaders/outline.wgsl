@@ -0,0 +1,96 @@
+#import bevy_pbr::mesh_view_bindings
+#import bevy_pbr::mesh_bindings
+
+#import bevy_pbr::mesh_functions
+
+struct OutlineProperties {
+    colors: array<vec4<f32>, 5>,
+    width: f32,
+    max_z_offset: f32,
+    width_adjust_zs: vec4<f32>,
+    width_adjust_scales: vec4<f32>,
+}
+
+@group(1) @binding(0)
+var light_map_tex: texture_2d<f32>;
+@group(1) @binding(1)
+var light_map_sampler: sampler;
+
+@group(1) @binding(2)
+var<uniform> properties: OutlineProperties;
+
+// _OutlineWidth is given in hundredths of a world unit
+let OUTLINE_WIDTH_UNIT: f32 = 0.01;
+
+fn saturate(value: f32) -> f32 {
+    return clamp(value, 0.0, 1.0);
+}
+
+// Keeps the outline width roughly constant on screen, interpolating the scales between the view depths
+fn outline_depth_scale(depth: f32) -> f32 {
+    let zs = properties.width_adjust_zs;
+    let scales = properties.width_adjust_scales;
+    if depth < zs.y {
+        let t = saturate((depth - zs.x) / max(zs.y - zs.x, 0.0001));
+        return mix(scales.x, scales.y, t);
+    }
+    let t = saturate((depth - zs.y) / max(zs.z - zs.y, 0.0001));
+    return mix(scales.y, scales.z, t);
+}
+
+struct Vertex {
+    @location(0) position: vec3<f32>,
+    @location(1) normal: vec3<f32>,
+    @location(2) uv: vec2<f32>,
+#ifdef VERTEX_COLORS
+    @location(3) color: vec4<f32>,
+#endif
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+};
+
+@vertex
+fn vertex(vertex: Vertex) -> VertexOutput {
+    var out: VertexOutput;
+
+    let world_position = mesh_position_local_to_world(mesh.model, vec4<f32>(vertex.position, 1.0));
+    let world_normal = mesh_normal_local_to_world(vertex.normal);
+
+    var view_position = view.inverse_view * world_position;
+    let view_normal = normalize((view.inverse_view * vec4<f32>(world_normal, 0.0)).xyz);
+    // the camera looks down -z
+    let depth = -view_position.z;
+
+    var width = properties.width * OUTLINE_WIDTH_UNIT * outline_depth_scale(depth);
+    var z_offset = 0.0;
+#ifdef VERTEX_COLORS
+    // the alpha is painted per vertex to thin the outline, the blue channel pushes it behind the mesh
+    width *= vertex.color.a;
+    z_offset = properties.max_z_offset * OUTLINE_WIDTH_UNIT * (1.0 - vertex.color.b);
+#endif
+
+    // flatten the normal so the hull grows sideways on screen and not towards the camera
+    let direction = normalize(vec3<f32>(view_normal.xy, -0.5));
+    view_position = vec4<f32>(view_position.xyz + direction * width - vec3<f32>(0.0, 0.0, z_offset), 1.0);
+
+    out.clip_position = view.projection * view_position;
+    out.uv = vertex.uv;
+    return out;
+}
+
+@fragment
+fn fragment(
+    @location(0) uv: vec2<f32>,
+) -> @location(0) vec4<f32> {
+#ifdef FACE
+    return vec4<f32>(properties.colors[0].rgb, 1.0);
+#else
+    let id_mask = textureSample(light_map_tex, light_map_sampler, uv).w;
+    // same material IDs as the cel shader, the first color belongs to ID 1
+    let material_id = clamp(i32(id_mask * 5.0), 1, 5);
+    return vec4<f32>(properties.colors[material_id - 1].rgb, 1.0);
+#endif
+}
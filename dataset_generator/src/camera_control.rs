@@ -1,6 +1,7 @@
+use bevy::core_pipeline::prepass::DepthPrepass;
 use bevy::input::mouse::{MouseMotion, MouseWheel};
 use bevy::prelude::*;
-use bevy::render::camera::Projection;
+use bevy::render::camera::{Projection, RenderTarget};
 
 // ANCHOR: example
 /// Tags an entity as capable of panning and orbiting.
@@ -122,13 +123,19 @@ fn get_primary_window_size(windows: &Res<Windows>) -> Vec2 {
     window
 }
 
-/// Spawn a camera like this
-pub fn spawn_camera(commands: &mut Commands) {
+/// Spawn a camera like this, rendering to `target` instead of the window when set
+pub fn spawn_camera(commands: &mut Commands, target: Option<Handle<Image>>) {
     let translation = Vec3::new(-2.0, 2.5, 5.0);
     let radius = translation.length();
 
     commands.spawn((
         Camera3dBundle {
+            camera: Camera {
+                target: target
+                    .map(RenderTarget::Image)
+                    .unwrap_or_default(),
+                ..Default::default()
+            },
             transform: Transform::from_translation(translation).looking_at(Vec3::ZERO, Vec3::Y),
             projection: bevy::prelude::Projection::Perspective(PerspectiveProjection {
                 fov: (5.0 / 360.0) * (std::f32::consts::PI * 2.0),
@@ -136,6 +143,8 @@ pub fn spawn_camera(commands: &mut Commands) {
             }),
             ..Default::default()
         },
+        // the cel shader reads it for the rim light
+        DepthPrepass,
         #[cfg(not(feature = "screenshot"))]
         PanOrbitCamera {
             radius,
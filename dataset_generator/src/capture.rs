@@ -0,0 +1,233 @@
+use std::num::NonZeroU32;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use bevy::prelude::*;
+use bevy::render::render_asset::RenderAssets;
+use bevy::render::render_graph::{self, NodeRunError, RenderGraph, RenderGraphContext};
+use bevy::render::render_resource::{
+    Buffer, BufferDescriptor, BufferUsages, Extent3d, ImageCopyBuffer, ImageDataLayout, MapMode,
+    TextureDimension, TextureFormat, TextureUsages, COPY_BYTES_PER_ROW_ALIGNMENT,
+};
+use bevy::render::renderer::{RenderContext, RenderDevice};
+use bevy::render::{RenderApp, RenderStage};
+
+pub const CAPTURE_NODE: &'static str = "capture_readback";
+
+type CaptureCallback = Box<dyn FnOnce(anyhow::Result<Image>) + Send + Sync>;
+
+struct CaptureRequest {
+    image: Handle<Image>,
+    callback: CaptureCallback,
+}
+
+struct InFlightCapture {
+    buffer: Buffer,
+    size: Extent3d,
+    padded_bytes_per_row: u32,
+    format: TextureFormat,
+    callback: CaptureCallback,
+}
+
+/// Pending readbacks, shared between the main and the render world.
+#[derive(Resource, Clone, Default)]
+pub struct CaptureQueue {
+    requests: Arc<Mutex<Vec<CaptureRequest>>>,
+    in_flight: Arc<Mutex<Vec<InFlightCapture>>>,
+    /// Requests whose callback has not been called yet
+    pending: Arc<AtomicUsize>,
+}
+
+impl CaptureQueue {
+    /// Copies `image` to the CPU after this frame is rendered and calls `callback` with the result,
+    /// or with the error if it cannot be read back.
+    pub fn capture(
+        &self,
+        image: Handle<Image>,
+        callback: impl FnOnce(anyhow::Result<Image>) + Send + Sync + 'static,
+    ) {
+        let pending = self.pending.clone();
+        pending.fetch_add(1, Ordering::SeqCst);
+        self.requests.lock().unwrap().push(CaptureRequest {
+            image,
+            callback: Box::new(move |image| {
+                callback(image);
+                pending.fetch_sub(1, Ordering::SeqCst);
+            }),
+        });
+    }
+
+    /// Whether every requested image has been handed to its callback.
+    pub fn is_idle(&self) -> bool {
+        self.pending.load(Ordering::SeqCst) == 0
+    }
+}
+
+/// Offscreen images the dataset camera renders into.
+#[derive(Resource, Clone)]
+pub struct CaptureTarget {
+    /// sRGB image for the shaded passes
+    pub color: Handle<Image>,
+    /// Linear image for the data passes, it stores the values written by the shader as they are
+    pub data: Handle<Image>,
+}
+
+/// Creates an image that can be used as a camera target and copied back to the CPU.
+pub fn create_target(
+    images: &mut Assets<Image>,
+    width: u32,
+    height: u32,
+    format: TextureFormat,
+) -> Handle<Image> {
+    let mut image = Image::new_fill(
+        Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        &[0, 0, 0, 255],
+        format,
+    );
+    image.texture_descriptor.usage = TextureUsages::TEXTURE_BINDING
+        | TextureUsages::COPY_SRC
+        | TextureUsages::COPY_DST
+        | TextureUsages::RENDER_ATTACHMENT;
+
+    images.add(image)
+}
+
+pub struct CapturePlugin;
+
+impl Plugin for CapturePlugin {
+    fn build(&self, app: &mut App) {
+        let queue = CaptureQueue::default();
+        app.insert_resource(queue.clone());
+
+        let render_app = app.sub_app_mut(RenderApp);
+        render_app
+            .insert_resource(queue)
+            .add_system_to_stage(RenderStage::Cleanup, read_captures);
+
+        let mut graph = render_app.world.resource_mut::<RenderGraph>();
+        graph.add_node(CAPTURE_NODE, CaptureNode);
+        graph
+            .add_node_edge(bevy::render::main_graph::node::CAMERA_DRIVER, CAPTURE_NODE)
+            .unwrap();
+    }
+}
+
+/// Copies the requested images into mappable buffers once all cameras are rendered.
+struct CaptureNode;
+
+impl render_graph::Node for CaptureNode {
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let queue = world.resource::<CaptureQueue>();
+        let gpu_images = world.resource::<RenderAssets<Image>>();
+
+        let mut requests = queue.requests.lock().unwrap();
+        let mut in_flight = queue.in_flight.lock().unwrap();
+        let mut not_ready = Vec::new();
+
+        for request in requests.drain(..) {
+            guard! { let Some(image) = gpu_images.get(&request.image) else {
+                // the target has not been uploaded yet, try again next frame
+                not_ready.push(request);
+                continue;
+            } }
+
+            let size = image.texture.size();
+            let format = image.texture_format;
+            let bytes_per_row = size.width * format.describe().block_size as u32;
+            let padded_bytes_per_row = align_to(bytes_per_row, COPY_BYTES_PER_ROW_ALIGNMENT);
+
+            let buffer = render_context.render_device.create_buffer(&BufferDescriptor {
+                label: Some("capture_buffer"),
+                size: (padded_bytes_per_row * size.height) as u64,
+                usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            });
+
+            render_context.command_encoder.copy_texture_to_buffer(
+                image.texture.as_image_copy(),
+                ImageCopyBuffer {
+                    buffer: &buffer,
+                    layout: ImageDataLayout {
+                        offset: 0,
+                        bytes_per_row: NonZeroU32::new(padded_bytes_per_row),
+                        rows_per_image: None,
+                    },
+                },
+                size,
+            );
+
+            in_flight.push(InFlightCapture {
+                buffer,
+                size,
+                padded_bytes_per_row,
+                format,
+                callback: request.callback,
+            });
+        }
+
+        requests.extend(not_ready);
+
+        Ok(())
+    }
+}
+
+/// Maps the buffers filled this frame and hands the images to their callbacks.
+fn read_captures(queue: Res<CaptureQueue>, render_device: Res<RenderDevice>) {
+    let captures: Vec<_> = queue.in_flight.lock().unwrap().drain(..).collect();
+    if captures.is_empty() {
+        return;
+    }
+
+    let mapped: Vec<_> = captures
+        .iter()
+        .map(|capture| {
+            let mapped = Arc::new(AtomicBool::new(false));
+            let result = mapped.clone();
+            render_device.map_buffer(&capture.buffer.slice(..), MapMode::Read, move |r| match r {
+                Ok(()) => result.store(true, Ordering::SeqCst),
+                Err(e) => error!("Cannot map capture buffer {:?}", e),
+            });
+            mapped
+        })
+        .collect();
+    // block until the copies are done, dataset generation does not care about the frame time
+    render_device.wgpu_device().poll(bevy::render::render_resource::Maintain::Wait);
+
+    for (capture, mapped) in captures.into_iter().zip(mapped) {
+        // the callback still runs, so the sample is failed instead of waited for
+        if !mapped.load(Ordering::SeqCst) {
+            (capture.callback)(Err(anyhow::anyhow!("the capture buffer could not be mapped")));
+            continue;
+        }
+
+        let bytes_per_row =
+            (capture.size.width * capture.format.describe().block_size as u32) as usize;
+        let data = {
+            let mapped = capture.buffer.slice(..).get_mapped_range();
+            mapped
+                .chunks(capture.padded_bytes_per_row as usize)
+                .take(capture.size.height as usize)
+                .flat_map(|row| &row[..bytes_per_row])
+                .copied()
+                .collect::<Vec<_>>()
+        };
+        capture.buffer.unmap();
+
+        let image = Image::new(capture.size, TextureDimension::D2, data, capture.format);
+        (capture.callback)(Ok(image));
+    }
+}
+
+fn align_to(value: u32, alignment: u32) -> u32 {
+    (value + alignment - 1) / alignment * alignment
+}
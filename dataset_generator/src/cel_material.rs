@@ -1,10 +1,15 @@
+use crate::lights::CelLights;
+use crate::material_loader::{MapKind, MaterialLoadReport};
 use crate::material_properties_types;
+use crate::outline_material::{OutlineMaterial, OutlineProperties};
 use bevy::{
     pbr::{MaterialPipeline, MaterialPipelineKey},
     prelude::*,
     render::{
         mesh::MeshVertexBufferLayout,
-        render_resource::{RenderPipelineDescriptor, ShaderType, SpecializedMeshPipelineError},
+        render_resource::{
+            Face, RenderPipelineDescriptor, ShaderType, SpecializedMeshPipelineError,
+        },
     },
 };
 use bevy::{
@@ -106,6 +111,7 @@ pub struct MaterialGlobalSpecular {
 #[derive(Debug, Clone, ShaderType, Default)]
 pub struct CelMaterialProperties {
     head_direction: Direction,
+    lights: CelLights,
     shadow_ramp_values: ShadowRamp,
     global_specular: MaterialGlobalSpecular,
 
@@ -120,6 +126,14 @@ pub struct CelMaterialProperties {
     specular_color: Color,
     hit_color: Color,
 
+    /// Tiling in xy and offset in zw of every bound map
+    main_tex_st: Vec4,
+    light_map_tex_st: Vec4,
+    face_map_tex_st: Vec4,
+    normal_map_tex_st: Vec4,
+    metal_map_tex_st: Vec4,
+    shadow_ramp_tex_st: Vec4,
+
     day_night_cycle: f32,
 
     use_shadow_ramp_texture: f32,
@@ -147,9 +161,26 @@ pub struct CelMaterialProperties {
     hit_color_fresnel_power: f32,
     hit_color_scaler: f32,
 
+    /// 0 adds the rim light to the color, anything else color dodges it
     rim_light_type: f32,
     rim_light_intensity: f32,
     rim_light_thickness: f32,
+
+    /// Diffuse alpha below this is discarded in the mask mode
+    alpha_cutoff: f32,
+    /// Opacity of the whole mesh in the dither mode
+    dither_alpha: f32,
+
+    /// 1 when the diffuse alpha is an emission mask
+    use_emission: f32,
+    emission_scaler: f32,
+    emission_scaler1: f32,
+    emission_scaler2: f32,
+    emission_scaler3: f32,
+    emission_scaler4: f32,
+    emission_scaler5: f32,
+    /// Blend from `emission_scaler` to the scaler of the material ID
+    emission_strength_lerp: f32,
 }
 
 impl Into<Color> for material_properties_types::Color {
@@ -163,8 +194,17 @@ impl From<material_properties_types::MaterialPropertiesRoot> for CelMaterialProp
         let properties = properties.m_saved_properties;
         let floats = properties.m_floats;
         let colors = properties.m_colors;
+        let tex_envs = properties.m_tex_envs;
+        let (_, alpha_cutoff) = CelAlphaMode::from_floats(&floats);
         CelMaterialProperties {
             head_direction: Default::default(),
+            lights: Default::default(),
+            main_tex_st: tex_envs.main_tex.scale_offset().into(),
+            light_map_tex_st: tex_envs.light_map_tex.scale_offset().into(),
+            face_map_tex_st: tex_envs.face_map_tex.scale_offset().into(),
+            normal_map_tex_st: tex_envs.bump_map.scale_offset().into(),
+            metal_map_tex_st: tex_envs.mtmap.scale_offset().into(),
+            shadow_ramp_tex_st: tex_envs.packed_shadow_ramp_tex.scale_offset().into(),
             day_night_cycle: 0.0,
             use_shadow_ramp_texture: floats.use_shadow_ramp,
             use_normal_map: floats.use_bump_map,
@@ -243,9 +283,110 @@ impl From<material_properties_types::MaterialPropertiesRoot> for CelMaterialProp
             use_fresnel: 1.0,
             hit_color_fresnel_power: floats.hit_color_fresnel_power,
             hit_color_scaler: floats.hit_color_scaler,
-            rim_light_type: 1.0,
-            rim_light_intensity: 1.0,
-            rim_light_thickness: 1.0,
+            // most material dumps do not have these, the defaults give a thin and faint additive rim
+            rim_light_type: floats.rim_light_type.unwrap_or(0.0),
+            rim_light_intensity: floats.rim_light_intensity.unwrap_or(0.3),
+            rim_light_thickness: floats.rim_light_thickness.unwrap_or(1.0),
+            alpha_cutoff,
+            dither_alpha: floats.dither_alpha,
+            use_emission: if floats.main_tex_alpha_use == 1.0 { 1.0 } else { 0.0 },
+            emission_scaler: floats.emission_scaler,
+            emission_scaler1: floats.emission_scaler1,
+            emission_scaler2: floats.emission_scaler2,
+            emission_scaler3: floats.emission_scaler3,
+            emission_scaler4: floats.emission_scaler4,
+            emission_scaler5: floats.emission_scaler5,
+            emission_strength_lerp: floats.emission_strength_lerp,
+        }
+    }
+}
+
+/// `_MainTexAlphaUse` value for a diffuse alpha that is transparency
+const MAIN_TEX_ALPHA_TRANSPARENCY: f32 = 3.0;
+
+/// How the diffuse alpha is used, derived from the clipping and dithering values of the material JSON.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum CelAlphaMode {
+    #[default]
+    Opaque,
+    /// Discarded below the cutoff, for hair cards and lace
+    Mask,
+    /// Discarded with an ordered dither pattern, scaled by `_DitherAlpha`
+    Dither,
+    Blend,
+}
+
+impl CelAlphaMode {
+    /// Mode of the material and the alpha cutoff used by the mask mode.
+    pub fn from_floats(floats: &material_properties_types::MFloats) -> (Self, f32) {
+        if floats.using_dither_alpha > 0.0 {
+            (CelAlphaMode::Dither, 0.0)
+        } else if floats.use_clipping > 0.0 {
+            (CelAlphaMode::Mask, floats.clip_alpha_threshold)
+        } else if floats.main_tex_alpha_use == MAIN_TEX_ALPHA_TRANSPARENCY {
+            // without a cutoff the alpha is blended
+            if floats.main_tex_alpha_cutoff > 0.0 {
+                (CelAlphaMode::Mask, floats.main_tex_alpha_cutoff)
+            } else {
+                (CelAlphaMode::Blend, 0.0)
+            }
+        } else {
+            (CelAlphaMode::Opaque, 0.0)
+        }
+    }
+
+    fn shader_def(&self) -> Option<&'static str> {
+        match self {
+            CelAlphaMode::Opaque => None,
+            CelAlphaMode::Mask => Some("ALPHA_MASK"),
+            CelAlphaMode::Dither => Some("ALPHA_DITHER"),
+            CelAlphaMode::Blend => Some("ALPHA_BLEND"),
+        }
+    }
+}
+
+/// Faces culled by the material, `_DrawBackFace` and back face UV2 shading need both sides.
+pub fn cull_mode_from_floats(floats: &material_properties_types::MFloats) -> Option<Face> {
+    if floats.draw_back_face > 0.0 || floats.use_back_face_uv2 > 0.0 {
+        return None;
+    }
+    // unity's CullMode enum
+    match floats.cull_mode as i32 {
+        1 => Some(Face::Front),
+        2 => Some(Face::Back),
+        _ => None,
+    }
+}
+
+/// Data written instead of the shaded color, used for the extra dataset passes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GBuffer {
+    WorldNormal,
+    ViewNormal,
+    /// View space depth divided by `MAX_LINEAR_DEPTH` in the shader, split over two channels for 16 bits
+    Depth,
+    /// Lightmap alpha, quantized the same way the shader picks the material parameters
+    MaterialId,
+    FaceMask,
+    /// Half lambert term that drives the shadow ramp
+    LightTerm,
+    /// Emission strength, divided by `MAX_EMISSION_STRENGTH` in the shader
+    Emission,
+    /// Mesh UV wrapped to 0-1, 12 bits per coordinate packed into the three channels
+    Uv,
+}
+
+impl GBuffer {
+    fn shader_def(&self) -> &'static str {
+        match self {
+            GBuffer::WorldNormal => "GBUFFER_WORLD_NORMAL",
+            GBuffer::ViewNormal => "GBUFFER_VIEW_NORMAL",
+            GBuffer::Depth => "GBUFFER_DEPTH",
+            GBuffer::MaterialId => "GBUFFER_MATERIAL_ID",
+            GBuffer::FaceMask => "GBUFFER_FACE_MASK",
+            GBuffer::LightTerm => "GBUFFER_LIGHT_TERM",
+            GBuffer::Emission => "GBUFFER_EMISSION",
+            GBuffer::Uv => "GBUFFER_UV",
         }
     }
 }
@@ -256,6 +397,15 @@ impl From<material_properties_types::MaterialPropertiesRoot> for CelMaterialProp
 pub struct CelMaterial {
     pub is_face: bool,
     pub diffuse_only: bool,
+    pub gbuffer: Option<GBuffer>,
+    pub alpha_mode: CelAlphaMode,
+    pub cull_mode: Option<Face>,
+    /// Sample the shadow map of the main light, see `LightShadows`
+    pub receive_shadows: bool,
+    /// Where the maps were loaded from and why the fallback was used, if it was
+    pub load_report: MaterialLoadReport,
+    /// Outline values of the material JSON, `None` for the fallback material
+    pub outline: Option<OutlineProperties>,
     #[texture(0)]
     #[sampler(1)]
     diffuse: Option<Handle<Image>>,
@@ -291,6 +441,12 @@ impl CelMaterial {
         Self {
             is_face: false,
             diffuse_only: false,
+            gbuffer: None,
+            alpha_mode: CelAlphaMode::Opaque,
+            cull_mode: None,
+            receive_shadows: false,
+            load_report: default(),
+            outline: None,
             diffuse: Some(diffuse),
             light_map: Some(light_map),
             metal_map: Some(metal_map),
@@ -312,6 +468,12 @@ impl CelMaterial {
         Self {
             is_face: true,
             diffuse_only: false,
+            gbuffer: None,
+            alpha_mode: CelAlphaMode::Opaque,
+            cull_mode: None,
+            receive_shadows: false,
+            load_report: default(),
+            outline: None,
             diffuse: Some(diffuse),
             face_light_map: Some(face_light_map),
             metal_map: Some(metal_map),
@@ -325,12 +487,70 @@ impl CelMaterial {
     pub fn update_head_direction(&mut self, head_direction: Direction) {
         self.properties.head_direction = head_direction;
     }
+
+    pub fn lights(&self) -> CelLights {
+        self.properties.lights
+    }
+
+    pub fn update_lights(&mut self, lights: CelLights) {
+        self.properties.lights = lights;
+    }
+
+    /// 0 uses the day shadow colors, 1 the night ones.
+    pub fn set_day_night_cycle(&mut self, day_night_cycle: f32) {
+        self.properties.day_night_cycle = day_night_cycle;
+    }
+
+    /// Textures of the material and the map they are bound as.
+    pub fn maps(&self) -> Vec<(MapKind, &Handle<Image>)> {
+        let light_map = if self.is_face {
+            MapKind::FaceShadow
+        } else {
+            MapKind::Lightmap
+        };
+        [
+            (MapKind::Diffuse, &self.diffuse),
+            (MapKind::FaceLightmap, &self.face_light_map),
+            (light_map, &self.light_map),
+            (MapKind::ShadowRamp, &self.shadow_ramp),
+            (MapKind::Normalmap, &self.normal_map),
+            (MapKind::MetalMap, &self.metal_map),
+        ]
+        .into_iter()
+        .filter_map(|(map, image)| image.as_ref().map(|image| (map, image)))
+        .collect()
+    }
+
+    /// Outline drawn around meshes using this material, `None` if `_OutlineType` is 0 or its width is zero.
+    pub fn outline_material(&self) -> Option<OutlineMaterial> {
+        let outline = self.outline.as_ref()?;
+        // the hull of clipped or transparent meshes would outline the whole quad
+        if self.diffuse_only || self.alpha_mode != CelAlphaMode::Opaque || outline.width() <= 0.0 {
+            return None;
+        }
+        // the face has a single outline color and no material IDs
+        let light_map = if self.is_face {
+            None
+        } else {
+            self.light_map.clone()
+        };
+        Some(OutlineMaterial::new(self.is_face, light_map, outline.clone()))
+    }
+
+    /// Materials created by `load_cel_material_fallback` have no textures.
+    pub fn is_fallback(&self) -> bool {
+        self.diffuse.is_none()
+    }
 }
 
 #[derive(Clone, PartialEq, Eq, Hash)]
 pub struct CelMaterialKey {
     is_face: bool,
     diffuse_only: bool,
+    gbuffer: Option<GBuffer>,
+    alpha_mode: CelAlphaMode,
+    cull_mode: Option<Face>,
+    receive_shadows: bool,
 }
 
 impl From<&CelMaterial> for CelMaterialKey {
@@ -338,10 +558,19 @@ impl From<&CelMaterial> for CelMaterialKey {
         CelMaterialKey {
             is_face: material.is_face,
             diffuse_only: material.diffuse_only,
+            gbuffer: material.gbuffer,
+            alpha_mode: material.alpha_mode,
+            cull_mode: material.cull_mode,
+            receive_shadows: material.receive_shadows,
         }
     }
 }
 
+/// The main pass also has the `DEPTH_PREPASS` key when the camera has a prepass, only the label tells them apart.
+fn is_prepass(descriptor: &RenderPipelineDescriptor) -> bool {
+    descriptor.label.as_deref() == Some("prepass_pipeline")
+}
+
 impl Material for CelMaterial {
     fn fragment_shader() -> ShaderRef {
         "shaders/cel_material.wgsl".into()
@@ -353,8 +582,17 @@ impl Material for CelMaterial {
         //"shaders/test.wgsl".into()
     }
 
+    fn prepass_fragment_shader() -> ShaderRef {
+        "shaders/cel_prepass.wgsl".into()
+    }
+
     fn alpha_mode(&self) -> AlphaMode {
-        AlphaMode::Opaque
+        // the shader discards by itself, the mask modes only need to be drawn after the opaque meshes
+        match self.alpha_mode {
+            CelAlphaMode::Opaque => AlphaMode::Opaque,
+            CelAlphaMode::Mask | CelAlphaMode::Dither => AlphaMode::Mask(0.5),
+            CelAlphaMode::Blend => AlphaMode::Blend,
+        }
     }
 
     fn specialize(
@@ -373,6 +611,18 @@ impl Material for CelMaterial {
 
         println!("has color {}", layout.contains(Mesh::ATTRIBUTE_COLOR));
 
+        descriptor.primitive.cull_mode = key.bind_group_data.cull_mode;
+        // the prepass and the shadow maps use bevy's vertex shader with its own vertex layout,
+        // cut out materials get `cel_prepass.wgsl` as the fragment stage
+        if is_prepass(descriptor) {
+            if let Some(fragment) = descriptor.fragment.as_mut() {
+                if let Some(alpha_mode) = key.bind_group_data.alpha_mode.shader_def() {
+                    fragment.shader_defs.push(alpha_mode.into());
+                }
+            }
+            return Ok(());
+        }
+
         let mut vertex_attributes = vec![
             Mesh::ATTRIBUTE_POSITION.at_shader_location(0),
             Mesh::ATTRIBUTE_NORMAL.at_shader_location(1),
@@ -387,6 +637,19 @@ impl Material for CelMaterial {
             shader_defs.push("SIMPLE".into());
         }
 
+        if key.bind_group_data.receive_shadows {
+            shader_defs.push("CEL_SHADOWS".into());
+        }
+
+        if let Some(alpha_mode) = key.bind_group_data.alpha_mode.shader_def() {
+            shader_defs.push(alpha_mode.into());
+        }
+
+        if let Some(gbuffer) = key.bind_group_data.gbuffer {
+            shader_defs.push("GBUFFER".into());
+            shader_defs.push(gbuffer.shader_def().into());
+        }
+
         if key.bind_group_data.is_face {
             shader_defs.push("FACE".into());
         } else {
@@ -412,8 +675,64 @@ impl Material for CelMaterial {
             fragment.shader_defs.extend(shader_defs);
         }
 
-        descriptor.primitive.cull_mode = None;
-
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material_properties_types::MFloats;
+
+    #[test]
+    fn alpha_mode_priority() {
+        let transparent = MFloats {
+            main_tex_alpha_use: MAIN_TEX_ALPHA_TRANSPARENCY,
+            ..default()
+        };
+        assert_eq!(CelAlphaMode::from_floats(&transparent), (CelAlphaMode::Blend, 0.0));
+
+        let cutoff = MFloats {
+            main_tex_alpha_cutoff: 0.5,
+            ..transparent.clone()
+        };
+        assert_eq!(CelAlphaMode::from_floats(&cutoff), (CelAlphaMode::Mask, 0.5));
+
+        let clipping = MFloats {
+            use_clipping: 1.0,
+            clip_alpha_threshold: 0.3,
+            ..cutoff.clone()
+        };
+        assert_eq!(CelAlphaMode::from_floats(&clipping), (CelAlphaMode::Mask, 0.3));
+
+        let dither = MFloats {
+            using_dither_alpha: 1.0,
+            ..clipping
+        };
+        assert_eq!(CelAlphaMode::from_floats(&dither), (CelAlphaMode::Dither, 0.0));
+
+        assert_eq!(CelAlphaMode::from_floats(&MFloats::default()), (CelAlphaMode::Opaque, 0.0));
+    }
+
+    #[test]
+    fn cull_mode_priority() {
+        let cull = |cull_mode| MFloats {
+            cull_mode,
+            ..default()
+        };
+        assert_eq!(cull_mode_from_floats(&cull(0.0)), None);
+        assert_eq!(cull_mode_from_floats(&cull(1.0)), Some(Face::Front));
+        assert_eq!(cull_mode_from_floats(&cull(2.0)), Some(Face::Back));
+
+        let back_face = MFloats {
+            draw_back_face: 1.0,
+            ..cull(2.0)
+        };
+        assert_eq!(cull_mode_from_floats(&back_face), None);
+        let back_face_uv2 = MFloats {
+            use_back_face_uv2: 1.0,
+            ..cull(2.0)
+        };
+        assert_eq!(cull_mode_from_floats(&back_face_uv2), None);
+    }
+}
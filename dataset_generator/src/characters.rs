@@ -0,0 +1,113 @@
+use std::path::Path;
+
+use anyhow::Context;
+use bevy::prelude::*;
+use bevy::reflect::TypeUuid;
+
+pub const CHARACTERS_FILENAME: &'static str = "characters.manifest.ron";
+
+/// Rough body type of a character. Only needed for characters the automatic framing gets wrong,
+/// it then moves the preset cameras by a fixed offset instead of following the head.
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum HeightCategory {
+    Lady,
+    Girl,
+    Loli,
+    Male,
+    Boy,
+    /// Framed from the head bone or the bounds
+    #[default]
+    Unknown,
+}
+
+impl HeightCategory {
+    /// Camera offset of the category, `None` for `Unknown`.
+    pub fn camera_offset(&self) -> Option<f32> {
+        match self {
+            HeightCategory::Lady => Some(0.15),
+            HeightCategory::Girl => Some(0.0),
+            HeightCategory::Loli => Some(-0.25),
+            HeightCategory::Male => Some(0.3),
+            HeightCategory::Boy => Some(0.09),
+            HeightCategory::Unknown => None,
+        }
+    }
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct CharacterEntry {
+    /// Scene asset path, e.g. `models/Characters/Amber/Default/Avatar_Girl_Bow_Ambor.fbx#Scene`
+    pub path: String,
+    /// Scale applied to the spawned scene. When not set the scene is spawned at 100
+    /// and auto-framing corrects the units.
+    #[serde(default)]
+    pub scale: Option<f32>,
+    /// Added to the light preset translation
+    #[serde(default)]
+    pub light_offset: Option<Vec3>,
+    /// Overrides the camera offset found by the framing
+    #[serde(default)]
+    pub height: HeightCategory,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Why the character is disabled, e.g. "no face"
+    #[serde(default)]
+    pub exclusion_reason: Option<String>,
+}
+
+impl CharacterEntry {
+    pub fn from_path(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            scale: None,
+            light_offset: None,
+            height: HeightCategory::Unknown,
+            enabled: true,
+            exclusion_reason: None,
+        }
+    }
+
+    pub fn scale(&self) -> f32 {
+        self.scale.unwrap_or(100.0)
+    }
+
+    pub fn light_offset(&self) -> Vec3 {
+        self.light_offset.unwrap_or(Vec3::ZERO)
+    }
+
+    /// Path of the model file relative to the assets folder, without the `#Scene` label.
+    pub fn model_path(&self) -> &str {
+        self.path.split('#').next().unwrap_or(&self.path)
+    }
+}
+
+#[derive(Clone, Default, Debug, TypeUuid, serde::Deserialize, serde::Serialize)]
+#[uuid = "8f5a2c7e-6a0b-4d8e-9f43-2b1c5d7e9a10"]
+pub struct CharacterManifest {
+    pub characters: Vec<CharacterEntry>,
+}
+
+impl CharacterManifest {
+    /// Reads the manifest directly from disk, for use outside of the bevy app.
+    pub fn load_from_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let manifest = std::fs::read_to_string(path)
+            .with_context(|| format!("Cannot read {}", path.display()))?;
+        ron::from_str(&manifest).with_context(|| format!("Cannot parse {}", path.display()))
+    }
+
+    pub fn enabled(&self) -> impl Iterator<Item = &CharacterEntry> {
+        self.characters.iter().filter(|c| c.enabled)
+    }
+
+    pub fn get(&self, path: &str) -> Option<&CharacterEntry> {
+        self.characters.iter().find(|c| c.path == path)
+    }
+}
+
+#[derive(Resource)]
+pub struct CharacterManifestResource(pub Handle<CharacterManifest>);
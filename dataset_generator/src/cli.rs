@@ -0,0 +1,217 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use bevy::prelude::Resource;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use rand::Rng;
+
+use crate::characters::CharacterEntry;
+use crate::framing::DEFAULT_PRESET_HEAD_HEIGHT;
+use crate::output::{DownscaleFilter, OutputLayout, Pass};
+
+/// Dataset generator for the cel shading models.
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Open the interactive viewer (default)
+    View,
+    /// Render input/target pairs for every selected character
+    #[cfg(feature = "screenshot")]
+    Generate(GenerateArgs),
+    /// Print the characters known to the generator
+    ListCharacters,
+    /// Check that character models, materials and presets can be found
+    Validate(ValidateArgs),
+}
+
+#[derive(Args, Clone, Resource)]
+pub struct GenerateArgs {
+    /// Only render characters whose asset path contains one of these strings (all when empty)
+    #[arg(short, long = "character")]
+    pub characters: Vec<String>,
+    /// Number of input/target pairs rendered per character
+    #[arg(short = 'n', long, default_value_t = 60)]
+    pub samples: usize,
+    /// Preset group used for camera and light placement
+    #[arg(short, long, default_value = "face2.5")]
+    pub preset_group: String,
+    /// Directory the samples are written to
+    #[arg(short, long, default_value = "./output")]
+    pub output: PathBuf,
+    /// How input and target images are written
+    #[arg(short, long, value_enum, default_value_t = OutputLayout::Split)]
+    pub layout: OutputLayout,
+    /// Extra passes rendered for every sample besides the input and target, e.g. `--passes depth,view-normal`
+    #[arg(long, value_enum, value_delimiter = ',')]
+    pub passes: Vec<Pass>,
+    /// Width and height of the rendered images
+    #[arg(short, long, default_value_t = 512)]
+    pub resolution: u32,
+    /// Render at this many times the resolution and downscale before writing
+    #[arg(long, default_value_t = 1)]
+    pub supersample: u32,
+    /// Filter used to downscale supersampled renders
+    #[arg(long, value_enum, default_value_t = DownscaleFilter::Box)]
+    pub downscale_filter: DownscaleFilter,
+    /// Run without a window, e.g. on a server with a software Vulkan driver
+    #[arg(long)]
+    pub headless: bool,
+    /// Seed for camera and light sampling, combined with the character and sample index for every sample
+    #[arg(short, long, default_value_t = 0)]
+    pub seed: u64,
+    /// Scale every character to this height in meters instead of only correcting unit mismatches
+    #[arg(long)]
+    pub target_height: Option<f32>,
+    /// Head height in meters the camera presets were authored for
+    #[arg(long, default_value_t = DEFAULT_PRESET_HEAD_HEIGHT)]
+    pub preset_head_height: f32,
+    /// Skip a character if its model has not loaded after this many seconds
+    #[arg(long, default_value_t = 120.0)]
+    pub load_timeout: f32,
+    /// Do not render samples whose images are already in the output directory, to continue an interrupted run
+    #[arg(long, alias = "resume")]
+    pub skip_existing: bool,
+    /// Shadow colors of every sample, between the day and night rows of the shadow ramp
+    #[arg(long, value_enum, default_value_t = DayNight::Day)]
+    pub day_night: DayNight,
+    /// Color temperature of the main light in kelvin, picked per sample, e.g. `2000..6500` for sunset to daylight.
+    /// The preset colors are used when unset
+    #[arg(long)]
+    pub light_temperature: Option<SampleRange>,
+    /// Factor of the light intensity, picked per sample, e.g. `0.6..1.3`
+    #[arg(long)]
+    pub light_intensity: Option<SampleRange>,
+    /// Let the main light cast shadows, e.g. of the hair onto the face
+    #[arg(long)]
+    pub shadows: bool,
+}
+
+/// `MIN..MAX` or a single value.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SampleRange {
+    pub min: f32,
+    pub max: f32,
+}
+
+impl SampleRange {
+    pub fn sample(&self, rng: &mut impl Rng) -> f32 {
+        if self.min < self.max {
+            rng.gen_range(self.min..=self.max)
+        } else {
+            self.min
+        }
+    }
+}
+
+impl FromStr for SampleRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |v: &str| match v.trim().parse::<f32>() {
+            Ok(value) if value.is_finite() => Ok(value),
+            Ok(_) => Err(format!("{:?} is not a finite number", v)),
+            Err(e) => Err(format!("invalid number {:?}: {}", v, e)),
+        };
+        let (min, max) = match s.split_once("..") {
+            Some((min, max)) => (parse(min)?, parse(max)?),
+            None => (parse(s)?, parse(s)?),
+        };
+        if min > max {
+            return Err(format!("{} is larger than {}", min, max));
+        }
+        Ok(SampleRange { min, max })
+    }
+}
+
+/// How the day/night cycle of the materials is picked for every sample.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DayNight {
+    #[default]
+    Day,
+    Night,
+    /// Day or night with equal probability
+    Random,
+    /// Uniformly between day and night
+    Blend,
+}
+
+impl DayNight {
+    pub fn sample(&self, rng: &mut impl Rng) -> f32 {
+        match self {
+            DayNight::Day => 0.0,
+            DayNight::Night => 1.0,
+            DayNight::Random => {
+                if rng.gen_bool(0.5) {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            DayNight::Blend => rng.gen_range(0.0..=1.0),
+        }
+    }
+}
+
+#[derive(Args, Clone)]
+pub struct ValidateArgs {
+    /// Preset group that must exist in the presets file
+    #[arg(short, long, default_value = "face2.5")]
+    pub preset_group: String,
+    /// Only validate characters whose asset path contains one of these strings (all when empty)
+    #[arg(short, long = "character")]
+    pub characters: Vec<String>,
+}
+
+/// Returns the characters whose path contains any of the filters, or all of them when there are no filters.
+pub fn filter_characters<'a>(
+    characters: impl IntoIterator<Item = &'a CharacterEntry>,
+    filters: &[String],
+) -> Vec<&'a CharacterEntry> {
+    characters
+        .into_iter()
+        .filter(|c| {
+            filters.is_empty()
+                || filters
+                    .iter()
+                    .any(|f| c.path.to_ascii_lowercase().contains(&f.to_ascii_lowercase()))
+        })
+        .collect()
+}
+
+impl GenerateArgs {
+    /// Input and target followed by the extra passes, in render order.
+    pub fn all_passes(&self) -> Vec<Pass> {
+        let mut passes = vec![Pass::Input, Pass::Target];
+        for pass in &self.passes {
+            if !passes.contains(pass) {
+                passes.push(*pass);
+            }
+        }
+        passes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_range_parsing() {
+        assert_eq!("2".parse(), Ok(SampleRange { min: 2.0, max: 2.0 }));
+        assert_eq!(" -1.5 .. 3 ".parse(), Ok(SampleRange { min: -1.5, max: 3.0 }));
+        assert_eq!("4..4".parse(), Ok(SampleRange { min: 4.0, max: 4.0 }));
+    }
+
+    #[test]
+    fn sample_range_rejects_invalid_ranges() {
+        for invalid in ["", "a", "1..", "..1", "3..1", "NaN", "0..NaN", "inf", "-inf..0"] {
+            assert!(invalid.parse::<SampleRange>().is_err(), "{:?} was accepted", invalid);
+        }
+    }
+}
@@ -0,0 +1,30 @@
+use std::path::Path;
+
+use anyhow::Context;
+use bevy::asset::LoadContext;
+use serde::de::DeserializeOwned;
+
+/// Reads a RON config file directly from disk, for use outside of the bevy app.
+pub fn read_config_file<T: DeserializeOwned>(path: impl AsRef<Path>) -> anyhow::Result<T> {
+    let path = path.as_ref();
+    let bytes = std::fs::read(path).with_context(|| format!("Cannot read {}", path.display()))?;
+    parse_config(&bytes, path)
+}
+
+/// Reads a RON config file of the assets folder from an asset loader, which runs inside the asset server
+/// and has no access to resources. Loaders read it every time, so edits apply to the next loaded model.
+pub async fn load_config_asset<T: DeserializeOwned>(
+    load_context: &LoadContext<'_>,
+    file_name: &str,
+) -> anyhow::Result<T> {
+    let path = Path::new(file_name);
+    let bytes = load_context
+        .read_asset_bytes(path)
+        .await
+        .with_context(|| format!("Cannot read {}", path.display()))?;
+    parse_config(&bytes, path)
+}
+
+fn parse_config<T: DeserializeOwned>(bytes: &[u8], path: &Path) -> anyhow::Result<T> {
+    ron::de::from_bytes(bytes).with_context(|| format!("Cannot parse {}", path.display()))
+}
@@ -0,0 +1,94 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use lazy_static::lazy_static;
+
+use crate::material_loader::{MaterialLoadError, MaterialLoadReport};
+
+pub const FAILURES_FILENAME: &'static str = "failures.json";
+
+/// Why a character could not be rendered.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum FailureReason {
+    /// The asset server could not load the model file
+    ModelLoadFailed,
+    /// The model was still loading after the timeout
+    LoadTimeout { seconds: f32 },
+    /// The cel material could not be created, e.g. because of a missing texture or material JSON
+    MaterialLoadFailed {
+        material: String,
+        error: MaterialLoadError,
+    },
+    /// The mesh has no tangents, the cel shader renders nothing for it
+    MissingTangents { mesh: String },
+    /// The mesh has no vertex colors, the cel shader renders nothing for it
+    MissingVertexColors { mesh: String },
+    /// Every mesh uses the diffuse only fallback material
+    NoCelMaterials,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct CharacterFailure {
+    pub character: String,
+    pub character_index: usize,
+    pub reasons: Vec<FailureReason>,
+}
+
+/// Characters skipped during the run, written to `failures.json` in the output directory.
+#[derive(Resource, Default)]
+pub struct FailureReport {
+    pub failures: Vec<CharacterFailure>,
+}
+
+impl FailureReport {
+    pub fn write(&self, output: &Path) -> anyhow::Result<()> {
+        let file = File::create(output.join(FAILURES_FILENAME))?;
+        serde_json::to_writer_pretty(BufWriter::new(file), &self.failures)?;
+        Ok(())
+    }
+}
+
+lazy_static! {
+    /// Load reports of every material, by model path. The loader runs inside the asset server
+    /// and has no access to the app, so they are collected here until the character is checked.
+    static ref MATERIAL_REPORTS: Mutex<HashMap<PathBuf, Vec<MaterialLoadReport>>> = Default::default();
+}
+
+pub fn record_material_report(model: &Path, report: MaterialLoadReport) {
+    MATERIAL_REPORTS
+        .lock()
+        .unwrap()
+        .entry(model.to_path_buf())
+        .or_default()
+        .push(report);
+}
+
+/// Latest report recorded for the material of the model.
+pub fn material_report(model: &Path, material: &str) -> Option<MaterialLoadReport> {
+    MATERIAL_REPORTS
+        .lock()
+        .unwrap()
+        .get(model)
+        .and_then(|reports| reports.iter().rev().find(|r| r.material == material).cloned())
+}
+
+/// Returns the materials of the model that could not be loaded and forgets its reports.
+pub fn take_material_issues(model: &Path) -> Vec<FailureReason> {
+    let reports = MATERIAL_REPORTS.lock().unwrap().remove(model).unwrap_or_default();
+    reports
+        .into_iter()
+        .filter_map(|report| match report.error {
+            // effect meshes have no diffuse texture and are hidden
+            Some(MaterialLoadError::MissingDiffuse) | None => None,
+            Some(error) => Some(FailureReason::MaterialLoadFailed {
+                material: report.material,
+                error,
+            }),
+        })
+        .collect()
+}
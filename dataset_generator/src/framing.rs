@@ -0,0 +1,238 @@
+use bevy::math::Vec3A;
+use bevy::prelude::*;
+use bevy::render::primitives::Aabb;
+
+use crate::cel_material::CelMaterial;
+use crate::CurrentCharacter;
+
+/// Height of the head the bundled camera presets were authored for.
+pub const DEFAULT_PRESET_HEAD_HEIGHT: f32 = 1.35;
+
+/// Names of the head bone in the character rigs.
+const HEAD_BONE_NAMES: &[&str] = &["Bip001 Head", "Head"];
+
+/// Distance from the top of the bounds to the head bone, relative to the character height,
+/// used when the model has no head bone.
+const HEAD_FROM_TOP: f32 = 0.07;
+
+/// Characters taller or shorter than this are assumed to be exported in the wrong units.
+const PLAUSIBLE_HEIGHT: std::ops::RangeInclusive<f32> = 0.3..=3.0;
+
+#[derive(Resource, Clone)]
+pub struct FramingConfig {
+    /// Height every character is scaled to. When not set only unit mismatches are corrected.
+    pub target_height: Option<f32>,
+    /// Head height the camera presets were authored for, they are moved by the difference to the framed head
+    pub preset_head_height: f32,
+}
+
+impl Default for FramingConfig {
+    fn default() -> Self {
+        Self {
+            target_height: None,
+            preset_head_height: DEFAULT_PRESET_HEAD_HEIGHT,
+        }
+    }
+}
+
+/// World-space bounds and head position of a spawned character, after scale correction.
+#[derive(Component, Debug, Clone)]
+pub struct CharacterFraming {
+    pub min: Vec3,
+    pub max: Vec3,
+    pub head: Vec3,
+    pub head_bone: bool,
+    /// Vertical offset applied to preset cameras so they look at this character's head,
+    /// or the offset of the height category set in the manifest
+    pub camera_offset: f32,
+}
+
+impl CharacterFraming {
+    pub fn height(&self) -> f32 {
+        self.max.y - self.min.y
+    }
+}
+
+pub struct FramingPlugin;
+
+impl Plugin for FramingPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<FramingConfig>()
+            .add_system(frame_characters);
+    }
+}
+
+fn frame_characters(
+    mut commands: Commands,
+    config: Res<FramingConfig>,
+    mut characters: Query<(Entity, &CurrentCharacter, &mut Transform), Without<CharacterFraming>>,
+    children: Query<&Children>,
+    names: Query<&Name>,
+    global_transforms: Query<&GlobalTransform>,
+    bounds: Query<(&Aabb, &GlobalTransform, Option<&Handle<CelMaterial>>)>,
+    materials: Res<Assets<CelMaterial>>,
+) {
+    for (entity, character, mut transform) in characters.iter_mut() {
+        let mut min = Vec3::splat(f32::MAX);
+        let mut max = Vec3::splat(f32::MIN);
+        let mut head = None;
+        let mut any = false;
+
+        for child in iter_descendants(entity, &children) {
+            if head.is_none() {
+                if let Ok(name) = names.get(child) {
+                    if HEAD_BONE_NAMES.contains(&name.as_str()) {
+                        head = Some(child);
+                    }
+                }
+            }
+
+            guard! { let Ok((aabb, global_transform, material)) = bounds.get(child) else { continue } }
+
+            // effect meshes use the diffuse only fallback and are hidden, they should not affect framing
+            let is_effect = material
+                .and_then(|m| materials.get(m))
+                .map(|m| m.diffuse_only)
+                .unwrap_or(false);
+            if is_effect {
+                continue;
+            }
+
+            for corner in aabb_corners(aabb) {
+                let corner = global_transform.affine().transform_point3a(corner);
+                min = min.min(corner.into());
+                max = max.max(corner.into());
+            }
+            any = true;
+        }
+
+        // the scene has not been spawned or its bounds are not calculated yet
+        if !any {
+            continue;
+        }
+
+        let height = max.y - min.y;
+        let correction = if character.0.scale.is_some() {
+            1.0
+        } else {
+            match config.target_height {
+                Some(target_height) if is_measurable(height) => target_height / height,
+                // also leaves degenerate bounds unscaled, with a warning
+                _ => unit_correction(height),
+            }
+        };
+
+        let root = transform.translation;
+        let rescale = |p: Vec3| root + (p - root) * correction;
+
+        let min = rescale(min);
+        let max = rescale(max);
+        let head_bone = head.and_then(|h| global_transforms.get(h).ok());
+        let head = match head_bone {
+            Some(head) => rescale(head.translation()),
+            None => Vec3::new(
+                (min.x + max.x) * 0.5,
+                max.y - (max.y - min.y) * HEAD_FROM_TOP,
+                (min.z + max.z) * 0.5,
+            ),
+        };
+        let head_bone = head_bone.is_some();
+
+        transform.scale *= correction;
+
+        let camera_offset = character
+            .0
+            .height
+            .camera_offset()
+            .unwrap_or(head.y - config.preset_head_height);
+
+        let framing = CharacterFraming {
+            min,
+            max,
+            head,
+            head_bone,
+            camera_offset,
+        };
+        info!(
+            "framed {}: height {:.2}, scale correction {}, head at {:.2} ({}), camera offset {:.2}",
+            character.0.path,
+            framing.height(),
+            correction,
+            framing.head.y,
+            if head_bone { "bone" } else { "bounds" },
+            camera_offset
+        );
+        if head.y <= min.y || head.y > max.y {
+            warn!("{}: head at {:.2} is outside of the bounds", character.0.path, head.y);
+        }
+
+        commands.entity(entity).insert(framing);
+    }
+}
+
+pub fn iter_descendants(entity: Entity, children: &Query<&Children>) -> Vec<Entity> {
+    let mut result = Vec::new();
+    let mut stack = vec![entity];
+    while let Some(entity) = stack.pop() {
+        if let Ok(c) = children.get(entity) {
+            result.extend(c.iter().copied());
+            stack.extend(c.iter().copied());
+        }
+    }
+    result
+}
+
+fn aabb_corners(aabb: &Aabb) -> [Vec3A; 8] {
+    let c = aabb.center;
+    let h = aabb.half_extents;
+    [
+        c + h * Vec3A::new(-1.0, -1.0, -1.0),
+        c + h * Vec3A::new(-1.0, -1.0, 1.0),
+        c + h * Vec3A::new(-1.0, 1.0, -1.0),
+        c + h * Vec3A::new(-1.0, 1.0, 1.0),
+        c + h * Vec3A::new(1.0, -1.0, -1.0),
+        c + h * Vec3A::new(1.0, -1.0, 1.0),
+        c + h * Vec3A::new(1.0, 1.0, -1.0),
+        c + h * Vec3A::new(1.0, 1.0, 1.0),
+    ]
+}
+
+/// Whether the bounds have a height that can be scaled, empty meshes or broken bounds have none.
+fn is_measurable(height: f32) -> bool {
+    height.is_finite() && height > 0.0
+}
+
+/// Power of ten that brings the height into the plausible range, e.g. 0.01 for a model exported in centimeters.
+fn unit_correction(height: f32) -> f32 {
+    if !is_measurable(height) {
+        warn!("cannot correct the units of a character with a height of {}", height);
+        return 1.0;
+    }
+    let mut correction = 1.0;
+    while height * correction > *PLAUSIBLE_HEIGHT.end() {
+        correction /= 10.0;
+    }
+    while height * correction < *PLAUSIBLE_HEIGHT.start() {
+        correction *= 10.0;
+    }
+    correction
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unit_correction_scales_by_powers_of_ten() {
+        assert_eq!(unit_correction(1.6), 1.0);
+        assert!((unit_correction(160.0) - 0.01).abs() < 1e-6);
+        assert!((unit_correction(0.016) - 100.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn unit_correction_ignores_degenerate_heights() {
+        for height in [0.0, -1.0, f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+            assert_eq!(unit_correction(height), 1.0);
+        }
+    }
+}
@@ -0,0 +1,579 @@
+#[cfg(feature = "screenshot")]
+use bevy::render::view::screenshot::ScreenshotManager;
+use std::path::Path;
+
+use bevy::app::AppExit;
+use bevy::asset::LoadState;
+use bevy::core_pipeline::clear_color::ClearColorConfig;
+use bevy::core_pipeline::tonemapping::{DebandDither, Tonemapping};
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use bevy::render::camera::RenderTarget;
+use bevy::window::WindowId;
+use rand::prelude::*;
+
+use crate::capture::{CaptureQueue, CaptureTarget};
+use crate::cel_material::CelMaterial;
+use crate::characters::CharacterEntry;
+use crate::cli::GenerateArgs;
+use crate::failures::{
+    take_material_issues, CharacterFailure, FailureReason, FailureReport, FAILURES_FILENAME,
+};
+use crate::framing::{iter_descendants, CharacterFraming};
+use crate::outline_material::Outline;
+use crate::lights::{apply_light_preset, color_temperature, LightShadows, SceneLight};
+use crate::metadata::{MaterialSettings, SampleLog, SampleRecord, SAMPLES_FILENAME};
+use crate::output::{Pass, SampleWriter, DATA_PASS_CLEAR_COLOR};
+use crate::{spawn_character, AppState, CurrentCharacter, PresetsGroups, PresetsResource};
+
+#[derive(Clone)]
+pub struct QueuedCharacter {
+    /// Position in the character manifest, written to the metadata
+    pub index: usize,
+    pub character: CharacterEntry,
+}
+
+/// Characters selected for the current run, in render order.
+#[derive(Resource, Clone)]
+pub struct GenerateQueue(pub Vec<QueuedCharacter>);
+
+/// Position of the run in the queue.
+#[derive(Resource, Default)]
+pub struct GenerateProgress {
+    /// Index in `GenerateQueue`
+    pub character: usize,
+    pub sample: usize,
+    /// Time the current character started loading
+    pub load_started: Option<f64>,
+    /// Whether the loaded character has been checked for material and mesh problems
+    pub checked: bool,
+    /// Whether the current character has been added to the failure report
+    pub failed: bool,
+}
+
+/// Name of the sample files, the same between runs so interrupted runs can be continued.
+/// Derived from the character path, so editing the manifest does not rename the samples of other characters.
+pub fn sample_name(character: &str, sample: usize) -> String {
+    format!("sample-{:016x}-{}", fnv1a(character.bytes()), sample)
+}
+
+/// Random generator for one sample, seeded from the run seed, the character path and the sample index,
+/// so any sample can be rendered again on its own.
+pub fn sample_rng(seed: u64, character: &str, sample: usize) -> StdRng {
+    let bytes = seed
+        .to_le_bytes()
+        .into_iter()
+        .chain(character.bytes())
+        .chain((sample as u64).to_le_bytes());
+    StdRng::seed_from_u64(fnv1a(bytes))
+}
+
+/// FNV-1a, unlike the std hasher it is stable across builds.
+fn fnv1a(bytes: impl IntoIterator<Item = u8>) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+pub struct GeneratePlugin;
+
+#[cfg(feature = "screenshot")]
+impl Plugin for GeneratePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<GenerateProgress>()
+            .init_resource::<FailureReport>()
+            .add_system(screenshot_on_f12)
+            .add_system(watch_loading)
+            .add_system(write_sample_records)
+            .add_system(exit_when_done.after(write_sample_records))
+            .add_system_set(
+                SystemSet::on_update(AppState::Ready)
+                    .with_system(check_character.before(generate))
+                    .with_system(generate),
+            )
+            .add_system_set(SystemSet::on_update(AppState::Failed).with_system(skip_failed));
+    }
+}
+
+#[cfg(not(feature = "screenshot"))]
+impl Plugin for GeneratePlugin {
+    fn build(&self, app: &mut App) {}
+}
+
+#[derive(PartialEq)]
+enum ScreenshotState {
+    SetupCamera,
+    /// Capturing the pass at this index of `GenerateArgs::all_passes`
+    Capture(usize),
+}
+
+impl Default for ScreenshotState {
+    fn default() -> Self {
+        Self::SetupCamera
+    }
+}
+
+/// Run configuration and position, shared by the systems that move between characters.
+#[derive(SystemParam)]
+struct GenerateRun<'w, 's> {
+    config: Res<'w, GenerateArgs>,
+    queue: Res<'w, GenerateQueue>,
+    progress: ResMut<'w, GenerateProgress>,
+    report: ResMut<'w, FailureReport>,
+    app_state: ResMut<'w, State<AppState>>,
+    asset_server: Res<'w, AssetServer>,
+    commands: Commands<'w, 's>,
+}
+
+impl<'w, 's> GenerateRun<'w, 's> {
+    fn current(&self) -> Option<QueuedCharacter> {
+        self.queue.0.get(self.progress.character).cloned()
+    }
+
+    fn is_done(&self) -> bool {
+        self.progress.character >= self.queue.0.len()
+    }
+
+    /// Starts loading the next character, or writes the failure report after the last one.
+    fn next_character(&mut self) {
+        self.progress.character += 1;
+        self.progress.sample = 0;
+        self.progress.load_started = None;
+        self.progress.checked = false;
+        self.progress.failed = false;
+
+        guard! { let Some(queued) = self.current() else {
+            if let Err(e) = self.report.write(&self.config.output) {
+                error!("Cannot write failure report {:?}", e);
+            }
+            info!(
+                "done! {} of {} characters failed, see {}",
+                self.report.failures.len(),
+                self.queue.0.len(),
+                FAILURES_FILENAME
+            );
+            return;
+        } }
+
+        self.set_state(AppState::Loading);
+        spawn_character(&mut self.commands, &self.asset_server, &queued.character);
+    }
+
+    /// Records why the current character cannot be rendered, `skip_failed` moves on to the next one.
+    fn fail(&mut self, reasons: Vec<FailureReason>) {
+        guard! { let Some(queued) = self.current() else { return } }
+        if self.progress.failed {
+            return;
+        }
+        self.progress.failed = true;
+        warn!("skipping {}: {:?}", queued.character.path, reasons);
+
+        self.report.failures.push(CharacterFailure {
+            character: queued.character.path,
+            character_index: queued.index,
+            reasons,
+        });
+        // keep the report up to date in case the run does not finish
+        if let Err(e) = self.report.write(&self.config.output) {
+            error!("Cannot write failure report {:?}", e);
+        }
+        self.set_state(AppState::Failed);
+    }
+
+    /// Replaces a transition queued earlier in the frame, e.g. by a scene that finished loading late.
+    fn set_state(&mut self, state: AppState) {
+        if let Err(e) = self.app_state.overwrite_set(state) {
+            error!("Cannot change the app state {:?}", e);
+        }
+    }
+}
+
+/// Captures the render target and writes the images and metadata of the samples.
+#[derive(SystemParam)]
+struct SampleOutput<'w, 's> {
+    capture: Res<'w, CaptureQueue>,
+    target: Res<'w, CaptureTarget>,
+    writer: Res<'w, SampleWriter>,
+    log: ResMut<'w, SampleLog>,
+    record: Local<'s, Option<SampleRecord>>,
+    camera: Query<
+        'w,
+        's,
+        (
+            &'static mut Camera,
+            &'static mut Camera3d,
+            &'static mut Tonemapping,
+            &'static mut DebandDither,
+        ),
+    >,
+}
+
+impl<'w, 's> SampleOutput<'w, 's> {
+    /// Renders the pass into its target. The main texture of a camera without hdr is sRGB, so data passes
+    /// render into a float one without tonemapping and dithering, and reach the linear target unchanged.
+    /// Their background is cleared to zero, which no surface writes.
+    fn set_pass(&mut self, pass: Pass) -> Handle<Image> {
+        let data = pass.gbuffer().is_some();
+        let target = if data {
+            self.target.data.clone()
+        } else {
+            self.target.color.clone()
+        };
+        for (mut camera, mut camera_3d, mut tonemapping, mut dither) in self.camera.iter_mut() {
+            camera.target = RenderTarget::Image(target.clone());
+            camera.hdr = data;
+            if data {
+                camera_3d.clear_color = ClearColorConfig::Custom(DATA_PASS_CLEAR_COLOR);
+                *tonemapping = Tonemapping::None;
+                *dither = DebandDither::Disabled;
+            } else {
+                camera_3d.clear_color = ClearColorConfig::Default;
+                *tonemapping = Tonemapping::default();
+                *dither = DebandDither::default();
+            }
+        }
+        target
+    }
+}
+
+/// Fails the current character if its model cannot be loaded or takes too long.
+#[cfg(feature = "screenshot")]
+fn watch_loading(
+    mut run: GenerateRun,
+    time: Res<Time>,
+    characters: Query<(&CurrentCharacter, &Handle<Scene>)>,
+) {
+    // a scene finishing late can switch to Ready before the current character is loaded,
+    // so keep watching until the character has been checked
+    if run.progress.checked || run.progress.failed {
+        return;
+    }
+    guard! { let Some(queued) = run.current() else { return } }
+
+    let now = time.elapsed_seconds_f64();
+    let started = *run.progress.load_started.get_or_insert(now);
+
+    let failed = characters
+        .iter()
+        .find(|(c, _)| c.0.path == queued.character.path)
+        .map(|(_, scene)| run.asset_server.get_load_state(scene) == LoadState::Failed)
+        .unwrap_or(false);
+
+    let reason = if failed {
+        FailureReason::ModelLoadFailed
+    } else if now - started > run.config.load_timeout as f64 {
+        FailureReason::LoadTimeout {
+            seconds: run.config.load_timeout,
+        }
+    } else {
+        return;
+    };
+
+    let mut reasons = vec![reason];
+    reasons.extend(take_material_issues(Path::new(queued.character.model_path())));
+    run.fail(reasons);
+}
+
+/// Checks a loaded character for materials that fell back to the diffuse only material
+/// and meshes the cel shader cannot draw.
+#[cfg(feature = "screenshot")]
+fn check_character(
+    mut run: GenerateRun,
+    characters: Query<(Entity, &CurrentCharacter), With<CharacterFraming>>,
+    children: Query<&Children>,
+    mesh_query: Query<(&Handle<Mesh>, &Handle<CelMaterial>, Option<&Name>)>,
+    meshes: Res<Assets<Mesh>>,
+    materials: Res<Assets<CelMaterial>>,
+) {
+    if run.progress.checked || run.progress.failed {
+        return;
+    }
+    guard! { let Some(queued) = run.current() else { return } }
+    // wait until the scene is spawned and measured
+    let entity = characters
+        .iter()
+        .find(|(_, c)| c.0.path == queued.character.path)
+        .map(|(e, _)| e);
+    guard! { let Some(entity) = entity else { return } }
+
+    let mut reasons = take_material_issues(Path::new(queued.character.model_path()));
+    let mut cel_meshes = 0;
+    for child in iter_descendants(entity, &children) {
+        guard! { let Ok((mesh, material, name)) = mesh_query.get(child) else { continue } }
+        // fallback meshes are hidden, their vertex layout does not matter
+        if materials.get(material).map(|m| m.diffuse_only).unwrap_or(true) {
+            continue;
+        }
+        cel_meshes += 1;
+
+        guard! { let Some(mesh) = meshes.get(mesh) else { continue } }
+        let name = name.map(|n| n.to_string()).unwrap_or_default();
+        if mesh.attribute(Mesh::ATTRIBUTE_TANGENT).is_none() {
+            reasons.push(FailureReason::MissingTangents { mesh: name.clone() });
+        }
+        if mesh.attribute(Mesh::ATTRIBUTE_COLOR).is_none() {
+            reasons.push(FailureReason::MissingVertexColors { mesh: name });
+        }
+    }
+    if cel_meshes == 0 {
+        reasons.push(FailureReason::NoCelMaterials);
+    }
+
+    if reasons.is_empty() {
+        run.progress.checked = true;
+    } else {
+        run.fail(reasons);
+    }
+}
+
+/// Quits after the last character once every captured image has been written.
+#[cfg(feature = "screenshot")]
+fn exit_when_done(
+    queue: Res<GenerateQueue>,
+    progress: Res<GenerateProgress>,
+    capture: Res<CaptureQueue>,
+    writer: Res<SampleWriter>,
+    log: Res<SampleLog>,
+    mut exit: EventWriter<AppExit>,
+) {
+    if progress.character >= queue.0.len() && capture.is_idle() && writer.is_idle() && log.is_idle() {
+        if log.missing() > 0 {
+            error!("{} rendered samples are missing from {}", log.missing(), SAMPLES_FILENAME);
+        }
+        exit.send(AppExit);
+    }
+}
+
+/// Logs the samples whose images have all been written.
+#[cfg(feature = "screenshot")]
+fn write_sample_records(mut log: ResMut<SampleLog>, writer: Res<SampleWriter>) {
+    log.flush(&writer);
+}
+
+/// Removes the failed character and loads the next one.
+#[cfg(feature = "screenshot")]
+fn skip_failed(mut run: GenerateRun, characters: Query<(Entity, &CurrentCharacter)>) {
+    guard! { let Some(queued) = run.current() else { return } }
+
+    for (entity, character) in characters.iter() {
+        if character.0.path == queued.character.path {
+            run.commands.entity(entity).despawn_recursive();
+        }
+    }
+    run.next_character();
+}
+
+#[cfg(feature = "screenshot")]
+fn generate(
+    mut skip_counter: Local<usize>,
+    mut run: GenerateRun,
+    mut presets_handle: Option<ResMut<PresetsResource>>,
+    mut presets: ResMut<Assets<PresetsGroups>>,
+    mut camera_query: Query<(&mut Transform, With<Camera>)>,
+    light_query: Query<Entity, With<SceneLight>>,
+    shadows: Res<LightShadows>,
+    mut materials_query: Query<&mut Handle<CelMaterial>>,
+    mut materials: ResMut<Assets<CelMaterial>>,
+    mut current_character_query: Query<(Entity, &mut Visibility), With<CurrentCharacter>>,
+    mut outline_query: Query<&mut Visibility, (With<Outline>, Without<CurrentCharacter>)>,
+    framing_query: Query<(&CurrentCharacter, &CharacterFraming)>,
+    mut output: SampleOutput,
+    mut state: Local<ScreenshotState>
+) {
+    if run.is_done() || !run.progress.checked {
+        return;
+    }
+
+    *skip_counter += 1;
+    if *skip_counter < 2 && *skip_counter % 2 == 0 {
+        return;
+    }
+
+    let config = run.config.clone();
+
+    if run.progress.sample >= config.samples {
+        current_character_query
+            .for_each_mut(|mut c| c.1.set(Box::new(Visibility::Hidden)).unwrap());
+
+        run.next_character();
+        return;
+    }
+
+    guard! { let Some(presets_handle) = presets_handle else { return; } }
+    if let Some(ref mut presets) = presets.get_mut(&presets_handle.0) {
+        guard! { let Some(presets) = presets.groups.get_mut(&config.preset_group) else { return } }
+        guard! { let Some(queued) = run.current() else { return } }
+        let QueuedCharacter { index, character } = queued;
+        let writer = output.writer.clone();
+
+        match *state {
+            ScreenshotState::SetupCamera => {
+                let framing = framing_query
+                    .iter()
+                    .find(|(c, _)| c.0.path == character.path)
+                    .map(|(_, f)| f);
+                // wait until the spawned scene has been measured
+                guard! { let Some(framing) = framing else { return } }
+
+                let sample_index = run.progress.sample;
+                let sample = sample_name(&character.path, sample_index);
+                if config.skip_existing && writer.exists(&sample, &config.all_passes()) {
+                    run.progress.sample += 1;
+                    return;
+                }
+
+                let mut rng = sample_rng(config.seed, &character.path, sample_index);
+                let camera_preset = rng.gen_range(0..presets.camera.len());
+                let mut camera = presets.camera[camera_preset].clone();
+                camera.translation.y += framing.camera_offset;
+
+                let light_preset = rng.gen_range(0..presets.light.len());
+                let mut light = presets.light[light_preset].preset();
+                light.offset(character.light_offset());
+
+                *camera_query.single_mut().0.as_mut() = camera;
+
+                // drawn after the presets so they stay the same for a seed
+                let day_night_cycle = config.day_night.sample(&mut rng);
+                if let Some(temperature) = config.light_temperature {
+                    let tint = color_temperature(temperature.sample(&mut rng));
+                    let [r, g, b, a] = light.main.color.as_rgba_f32();
+                    light.main.color = Color::rgba(tint.r() * r, tint.g() * g, tint.b() * b, a);
+                }
+                if let Some(intensity) = config.light_intensity {
+                    let factor = intensity.sample(&mut rng);
+                    light.main.intensity *= factor;
+                    if let Some(fill) = &mut light.fill {
+                        fill.intensity *= factor;
+                    }
+                }
+                apply_light_preset(&mut run.commands, light_query.iter(), &light, *shadows);
+
+                let mut material_settings = MaterialSettings {
+                    day_night_cycle,
+                    ..default()
+                };
+                materials_query.for_each(|mat| {
+                    if let Some(mat) = materials.get_mut(mat) {
+                        if mat.is_fallback() {
+                            material_settings.fallback_materials += 1;
+                        } else {
+                            mat.set_day_night_cycle(day_night_cycle);
+                        }
+                    }
+                });
+
+                *output.record = Some(SampleRecord {
+                    character: character.path.clone(),
+                    character_index: index,
+                    sample_index,
+                    seed: config.seed,
+                    preset_group: config.preset_group.clone(),
+                    camera_preset,
+                    light_preset,
+                    camera,
+                    light,
+                    materials: material_settings,
+                    layout: writer.layout,
+                    input: writer.file_name(&sample, Pass::Input),
+                    target: writer.file_name(&sample, Pass::Target),
+                    passes: config
+                        .all_passes()
+                        .into_iter()
+                        .filter(|p| !matches!(p, Pass::Input | Pass::Target))
+                        .map(|p| (p, writer.file_name(&sample, p)))
+                        .collect(),
+                    sample,
+                });
+
+                *state = ScreenshotState::Capture(0);
+            }
+            ScreenshotState::Capture(pass_index) => {
+                let passes = config.all_passes();
+                let pass = passes[pass_index];
+
+                materials_query.for_each_mut(|mat| {
+                    if let Some(ref mut mat) = materials.get_mut(mat.as_ref()) {
+                        mat.diffuse_only = pass == Pass::Input;
+                        mat.gbuffer = pass.gbuffer();
+                    }
+                });
+                // outlines are part of the drawn style, the other passes show the surface only
+                let outline_visibility = if pass == Pass::Target {
+                    Visibility::Inherited
+                } else {
+                    Visibility::Hidden
+                };
+                outline_query.for_each_mut(|mut v| v.set(Box::new(outline_visibility)).unwrap());
+
+                guard! { let Some(sample) = output.record.clone() else { return } }
+                let name = sample.sample.clone();
+                writer.request(&name);
+                let target = output.set_pass(pass);
+                output.capture.capture(target, move |image| match image {
+                    Ok(image) => writer.submit(&name, pass, image),
+                    Err(e) => writer.fail(&name, pass, e),
+                });
+
+                if pass_index + 1 < passes.len() {
+                    *state = ScreenshotState::Capture(pass_index + 1);
+                } else {
+                    output.log.queue(sample);
+                    *output.record = None;
+                    run.progress.sample += 1;
+                    *state = ScreenshotState::SetupCamera;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "screenshot")]
+fn screenshot_on_f12(
+    input: Res<Input<KeyCode>>,
+    //main_window: Query<Entity, With<PrimaryWindow>>,
+    screenshot_manager: Option<ResMut<ScreenshotManager>>,
+    mut counter: Local<u32>,
+) {
+    // there is no window to take a screenshot of in headless runs
+    guard! { let Some(mut screenshot_manager) = screenshot_manager else { return } }
+
+    if input.just_pressed(KeyCode::F12) {
+        let path = format!("./screenshot-{}.png", *counter);
+        *counter += 1;
+        screenshot_manager
+            .save_screenshot_to_disk(WindowId::primary(), path)
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fnv1a_reference_values() {
+        assert_eq!(fnv1a(*b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(*b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(fnv1a(*b"foobar"), 0x85944171f73967e8);
+    }
+
+    #[test]
+    fn sample_names_are_stable() {
+        assert_eq!(sample_name("", 3), "sample-cbf29ce484222325-3");
+        assert_eq!(sample_name("a", 0), "sample-af63dc4c8601ec8c-0");
+    }
+
+    #[test]
+    fn sample_rng_depends_on_all_inputs() {
+        let draw = |seed, character, sample| sample_rng(seed, character, sample).gen::<[u64; 4]>();
+        let first = draw(7, "characters/a.fbx", 2);
+        assert_eq!(first, draw(7, "characters/a.fbx", 2));
+        assert_ne!(first, draw(8, "characters/a.fbx", 2));
+        assert_ne!(first, draw(7, "characters/b.fbx", 2));
+        assert_ne!(first, draw(7, "characters/a.fbx", 3));
+    }
+}
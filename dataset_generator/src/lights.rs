@@ -0,0 +1,319 @@
+use bevy::prelude::*;
+use bevy::render::render_resource::ShaderType;
+
+use crate::cel_material::CelMaterial;
+
+/// Bevy light units for an intensity of 1, only used by bevy's own materials.
+const POINT_LIGHT_LUMENS: f32 = 1500.0;
+const DIRECTIONAL_LIGHT_LUX: f32 = 10000.0;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LightKind {
+    #[default]
+    Point,
+    /// Shines along the forward direction of its transform, like the sun in the game
+    Directional,
+}
+
+/// One light of a preset.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct LightSettings {
+    #[serde(default)]
+    pub kind: LightKind,
+    pub transform: Transform,
+    #[serde(default = "default_color")]
+    pub color: Color,
+    /// Multiplier of the cel shaded light, 1 is the neutral look of the game
+    #[serde(default = "default_intensity")]
+    pub intensity: f32,
+}
+
+fn default_color() -> Color {
+    Color::WHITE
+}
+
+fn default_intensity() -> f32 {
+    1.0
+}
+
+impl LightSettings {
+    pub fn point(transform: Transform) -> Self {
+        Self {
+            kind: LightKind::Point,
+            transform,
+            color: default_color(),
+            intensity: default_intensity(),
+        }
+    }
+}
+
+/// Lights placed by a preset. The main light drives the cel shading ramp, the fill light only adds a soft lambert term.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct LightPreset {
+    pub main: LightSettings,
+    #[serde(default)]
+    pub fill: Option<LightSettings>,
+}
+
+impl LightPreset {
+    /// Moves the lights by the offset of the character.
+    pub fn offset(&mut self, offset: Vec3) {
+        self.main.transform.translation += offset;
+        if let Some(fill) = &mut self.fill {
+            fill.transform.translation += offset;
+        }
+    }
+}
+
+/// Light entry of the presets file.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+pub enum LightPresetEntry {
+    Lights(LightPreset),
+    /// Older presets only place a white point light
+    Position(Transform),
+}
+
+impl LightPresetEntry {
+    pub fn preset(&self) -> LightPreset {
+        match self {
+            LightPresetEntry::Lights(preset) => preset.clone(),
+            LightPresetEntry::Position(transform) => LightPreset {
+                main: LightSettings::point(*transform),
+                fill: None,
+            },
+        }
+    }
+}
+
+/// Light of the scene, its settings are copied into every cel material.
+#[derive(Component, Clone, Debug)]
+pub struct SceneLight {
+    pub kind: LightKind,
+    pub color: Color,
+    pub intensity: f32,
+}
+
+#[derive(Component)]
+pub struct MainLight;
+
+/// Whether the main light casts shadows onto the cel materials.
+#[derive(Resource, Clone, Copy, Debug, Default)]
+pub struct LightShadows(pub bool);
+
+#[derive(Component)]
+pub struct FillLight;
+
+/// Lights as the cel shader sees them.
+#[derive(Debug, Clone, Copy, PartialEq, ShaderType)]
+pub struct CelLights {
+    /// Position with w 1 for point lights, direction to the light with w 0 for directional lights
+    pub main_position: Vec4,
+    /// Linear color times intensity, the intensity also in w
+    pub main_color: Vec4,
+    pub fill_position: Vec4,
+    /// Zero without a fill light
+    pub fill_color: Vec4,
+}
+
+impl Default for CelLights {
+    fn default() -> Self {
+        Self {
+            main_position: Vec4::new(4.0, 8.0, 4.0, 1.0),
+            main_color: Vec4::ONE,
+            fill_position: Vec4::ZERO,
+            fill_color: Vec4::ZERO,
+        }
+    }
+}
+
+fn shader_position(transform: &Transform, kind: LightKind) -> Vec4 {
+    match kind {
+        LightKind::Point => transform.translation.extend(1.0),
+        LightKind::Directional => transform.back().extend(0.0),
+    }
+}
+
+fn shader_color(light: &SceneLight) -> Vec4 {
+    let [r, g, b, _] = light.color.as_linear_rgba_f32();
+    Vec3::new(r, g, b).extend(1.0) * light.intensity
+}
+
+/// Color of a black body at the temperature, from about 1000 K (candle) to 40000 K (blue sky).
+/// Tanner Helland's fit of the CIE data.
+pub fn color_temperature(kelvin: f32) -> Color {
+    let t = kelvin.clamp(1000.0, 40000.0) / 100.0;
+    let red = if t <= 66.0 {
+        255.0
+    } else {
+        329.69873 * (t - 60.0).powf(-0.13320476)
+    };
+    let green = if t <= 66.0 {
+        99.4708 * t.ln() - 161.11957
+    } else {
+        288.12217 * (t - 60.0).powf(-0.07551485)
+    };
+    let blue = if t >= 66.0 {
+        255.0
+    } else if t <= 19.0 {
+        0.0
+    } else {
+        138.51773 * (t - 10.0).ln() - 305.0448
+    };
+    Color::rgb(
+        red.clamp(0.0, 255.0) / 255.0,
+        green.clamp(0.0, 255.0) / 255.0,
+        blue.clamp(0.0, 255.0) / 255.0,
+    )
+}
+
+/// Only the main light casts shadows. They are set at spawn, the lights of a sample are rendered in the same frame.
+pub fn spawn_light(commands: &mut Commands, settings: &LightSettings, main: bool, shadows: LightShadows) {
+    let shadows_enabled = main && shadows.0;
+    let scene_light = SceneLight {
+        kind: settings.kind,
+        color: settings.color,
+        intensity: settings.intensity,
+    };
+    let mut entity = match settings.kind {
+        LightKind::Point => commands.spawn(PointLightBundle {
+            point_light: PointLight {
+                intensity: POINT_LIGHT_LUMENS * settings.intensity,
+                color: settings.color,
+                shadows_enabled,
+                ..default()
+            },
+            transform: settings.transform,
+            ..default()
+        }),
+        LightKind::Directional => commands.spawn(DirectionalLightBundle {
+            directional_light: DirectionalLight {
+                illuminance: DIRECTIONAL_LIGHT_LUX * settings.intensity,
+                color: settings.color,
+                shadows_enabled,
+                ..default()
+            },
+            transform: settings.transform,
+            ..default()
+        }),
+    };
+    entity.insert(scene_light);
+    if main {
+        entity.insert((MainLight, Name::new("Main light")));
+    } else {
+        entity.insert((FillLight, Name::new("Fill light")));
+    }
+}
+
+/// Replaces the lights of the scene with the ones of the preset.
+pub fn apply_light_preset(
+    commands: &mut Commands,
+    lights: impl IntoIterator<Item = Entity>,
+    preset: &LightPreset,
+    shadows: LightShadows,
+) {
+    for light in lights {
+        commands.entity(light).despawn();
+    }
+    spawn_light(commands, &preset.main, true, shadows);
+    if let Some(fill) = &preset.fill {
+        spawn_light(commands, fill, false, shadows);
+    }
+}
+
+/// Copies the scene lights into the cel materials that do not have them yet.
+pub fn sync_cel_lights(
+    main_query: Query<(&Transform, &SceneLight), With<MainLight>>,
+    fill_query: Query<(&Transform, &SceneLight), With<FillLight>>,
+    materials_query: Query<&Handle<CelMaterial>>,
+    mut materials: ResMut<Assets<CelMaterial>>,
+) {
+    guard! { let Some((transform, main)) = main_query.iter().next() else { return } }
+    let mut lights = CelLights {
+        main_position: shader_position(transform, main.kind),
+        main_color: shader_color(main),
+        ..default()
+    };
+    if let Some((transform, fill)) = fill_query.iter().next() {
+        lights.fill_position = shader_position(transform, fill.kind);
+        lights.fill_color = shader_color(fill);
+    }
+
+    materials_query.for_each(|handle| {
+        // only touch changed materials, get_mut makes the bind group rebuild
+        let changed = materials.get(handle).map(|m| m.lights() != lights).unwrap_or(false);
+        if changed {
+            if let Some(mat) = materials.get_mut(handle) {
+                mat.update_lights(lights);
+            }
+        }
+    });
+}
+
+/// Applies changes of `LightShadows` made in the UI to the main light and the cel materials.
+/// The cel shader looks up the shadow map of the light that has shadows enabled.
+pub fn sync_light_shadows(
+    shadows: Res<LightShadows>,
+    mut point_query: Query<&mut PointLight, With<MainLight>>,
+    mut directional_query: Query<&mut DirectionalLight, With<MainLight>>,
+    materials_query: Query<&Handle<CelMaterial>>,
+    mut materials: ResMut<Assets<CelMaterial>>,
+) {
+    let enabled = shadows.0;
+    for mut light in point_query.iter_mut() {
+        if light.shadows_enabled != enabled {
+            light.shadows_enabled = enabled;
+        }
+    }
+    for mut light in directional_query.iter_mut() {
+        if light.shadows_enabled != enabled {
+            light.shadows_enabled = enabled;
+        }
+    }
+
+    materials_query.for_each(|handle| {
+        let changed = materials
+            .get(handle)
+            .map(|m| m.receive_shadows != enabled)
+            .unwrap_or(false);
+        if changed {
+            if let Some(mat) = materials.get_mut(handle) {
+                mat.receive_shadows = enabled;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rgb(kelvin: f32) -> [f32; 3] {
+        let [r, g, b, _] = color_temperature(kelvin).as_rgba_f32();
+        [r, g, b]
+    }
+
+    #[test]
+    fn color_temperature_known_points() {
+        // the fit meets white at 6600 K
+        for channel in rgb(6600.0) {
+            assert!((channel - 1.0).abs() < 0.01, "{:?}", rgb(6600.0));
+        }
+        // candle light has no blue
+        let [r, g, b] = rgb(1500.0);
+        assert_eq!(r, 1.0);
+        assert!(g > 0.3 && g < 0.5, "{}", g);
+        assert_eq!(b, 0.0);
+        // clear sky is blue
+        let [r, g, b] = rgb(15000.0);
+        assert!(r < g && g < b, "{:?}", [r, g, b]);
+        assert_eq!(b, 1.0);
+    }
+
+    #[test]
+    fn color_temperature_is_clamped() {
+        assert_eq!(rgb(0.0), rgb(1000.0));
+        assert_eq!(rgb(100000.0), rgb(40000.0));
+    }
+}
@@ -1,18 +1,14 @@
-#![feature(result_option_inspect)]
-
-#[macro_use]
-extern crate lazy_static;
-
 use std::fs::{self, File};
 use std::io::{Read, Write};
+use std::path::Path;
 
 use bevy::reflect::serde::{ReflectSerializer, UntypedReflectDeserializer};
 use bevy::reflect::TypeUuid;
-#[cfg(feature = "screenshot")]
-use bevy::render::view::screenshot::ScreenshotManager;
+use bevy::render::render_resource::TextureFormat;
 use bevy::tasks::IoTaskPool;
+use bevy::app::{PluginGroupBuilder, ScheduleRunnerPlugin};
 use bevy::utils::HashMap;
-use bevy::window::WindowId;
+use bevy::winit::WinitPlugin;
 //use bevy::window::PrimaryWindow;
 use bevy::{asset::load_internal_asset, prelude::*};
 use bevy_common_assets::ron::RonAssetPlugin;
@@ -25,21 +21,49 @@ use bevy_mod_fbx::{FbxLoader, FbxMaterialLoaders, FbxMesh, FbxScene, FbxPlugin};
 //use bevy_prototype_debug_lines::{DebugLines, DebugLinesPlugin};
 use camera_control::{pan_orbit_camera, spawn_camera};
 use cel_material::CelMaterial;
-
-use material_loader::{load_cel_material, load_cel_material_fallback};
-use rand::prelude::*;
+use characters::{
+    CharacterEntry, CharacterManifest, CharacterManifestResource, CHARACTERS_FILENAME,
+};
+use clap::Parser;
+use cli::{Cli, Command, GenerateArgs, ValidateArgs};
+use framing::{CharacterFraming, FramingConfig, FramingPlugin};
+use capture::{CapturePlugin, CaptureTarget};
+use generate::{sample_name, GeneratePlugin, GenerateQueue, QueuedCharacter};
+
+use lights::{
+    spawn_light, sync_cel_lights, sync_light_shadows, LightPresetEntry, LightSettings, LightShadows,
+};
+use material_loader::{fix_color_spaces, load_cel_material, load_cel_material_fallback};
+use material_paths::{MaterialPaths, MATERIAL_PATHS_FILENAME};
+use outline_material::OutlinePlugin;
+use texture_rules::{TextureRules, TEXTURE_RULES_FILENAME};
 use serde::Deserialize;
 
 #[macro_use]
 extern crate guard;
 
 pub mod camera_control;
+pub mod capture;
 pub mod cel_material;
+pub mod characters;
+pub mod cli;
+pub mod config;
+pub mod failures;
+pub mod framing;
+pub mod generate;
+pub mod lights;
 pub mod material_loader;
+pub mod material_paths;
 pub mod material_properties_types;
+pub mod metadata;
+pub mod outline_material;
+pub mod output;
+pub mod texture_rules;
 
 const CEL_UTILS_HANDLE: HandleUntyped =
     HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 31698701027590);
+const CEL_MATERIAL_BINDINGS_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 31698701027591);
 
 struct CelShaderPlugin;
 
@@ -51,11 +75,17 @@ impl Plugin for CelShaderPlugin {
             "shaders/utils.wgsl",
             Shader::from_wgsl
         );
+        load_internal_asset!(
+            app,
+            CEL_MATERIAL_BINDINGS_HANDLE,
+            "shaders/cel_material_bindings.wgsl",
+            Shader::from_wgsl
+        );
     }
 }
 
 #[derive(Component)]
-struct CurrentCharacter;
+struct CurrentCharacter(CharacterEntry);
 
 #[derive(Clone, Eq, PartialEq, Debug, Hash)]
 enum AppState {
@@ -67,7 +97,7 @@ enum AppState {
 #[derive(Clone, Default, serde::Deserialize, serde::Serialize)]
 struct Presets {
     camera: Vec<Transform>,
-    light: Vec<Transform>,
+    light: Vec<LightPresetEntry>,
 }
 
 #[derive(Clone, Default, TypeUuid, serde::Deserialize, serde::Serialize)]
@@ -81,137 +111,6 @@ struct PresetsResource(Handle<PresetsGroups>);
 
 const PRESETS_FILENAME: &'static str = "presets_new.ron";
 
-const CHARACTERS: &'static [&'static str] = &[
-    "models/Characters/Baizhu/NPC_Avatar_Male_Catalyst_Baizhu (merge).fbx#Scene",
-    "models/Characters/Barbara/Summer/Avatar_Girl_Catalyst_BarbaraCostumeSummertime.fbx#Scene",
-    //broken tangent mapping mode "models/Characters/Childe/Avatar_Male_Bow_Tartaglia_Remote (merge).fbx#Scene",
-    "models/Characters/Cyno/NPC_Avatar_Boy_Pole_Cyno.fbx#Scene",
-    "models/Characters/Diona/Avatar_Loli_Bow_Diona_Remote (merge).fbx#Scene",
-    //"models/Characters/Dvalin/Cs_Monster_Dvalin_S04.fbx#Scene",
-    "models/Characters/Fischl/Default/Avatar_Girl_Bow_Fischl_Remote (merge).fbx#Scene",
-    //big 
-    "models/Characters/Kaveh/NPC_Avatar_Male_Claymore_Kaveh.fbx#Scene",
-    //"models/Characters/La Signora/Cs_Monster_LaSignora (merge).fbx#Scene",
-    //noface "models/Characters/Naganohara Yoimiya/Avatar_Girl_Bow_Yoimiya_Remote (merge).fbx#Scene",
-    "models/Characters/Rosaria/Censored/Avatar_Lady_Pole_Rosaria.fbx#Scene",
-    //no face "models/Characters/Scaramouche/NPC_Avatar_Boy_Catalyst_Scaramouche (merge).fbx#Scene",
-    //no face "models/Characters/Thoma/NPC_Coop_Avatar_Male_Pole_Tohma_Edit (merge).fbx#Scene",
-    //no face "models/Characters/Yun Jin/Avatar_Girl_Pole_Yunjin (merge).fbx#Scene",
-    
-    "models/Characters/Qiqi/Avatar_Loli_Sword_Qiqi_Remote (merge).fbx#Scene",
-
-    "models/Characters/Kamisato Ayaka/NPC_Avatar_Girl_Sword_Ayaka (merge).fbx#Scene",
-    "models/Characters/Mona/Default/NPC_Avatar_Girl_Catalyst_Mona (merge).fbx#Scene",
-    "models/Characters/Aether/Cs_Avatar_Boy_Sword_PlayerBoy.fbx#Scene",
-    "models/Characters/Candace/NPC_Avatar_Lady_Pole_Candace.fbx#Scene",
-    "models/Characters/Amber/Default/Avatar_Girl_Bow_Ambor.fbx#Scene",
-    "models/Characters/Albedo/Cs_Avatar_Boy_Sword_Albedo.fbx#Scene",
-    "models/Characters/Alhatham/NPC_Avatar_Male_Sword_Alhatham.fbx#Scene",
-    "models/Characters/Aloy/Avatar_Girl_Bow_Aloy (merge).fbx#Scene",
-    "models/Characters/Arataki Itto/Avatar_Male_Claymore_Itto_Remote (merge).fbx#Scene",
-    "models/Characters/Barbara/Default/Avatar_Girl_Catalyst_Barbara.fbx#Scene",
-    "models/Characters/Beidou/Avatar_Lady_Claymore_Beidou_Remote (merge).fbx#Scene",
-    "models/Characters/Bennett/NPC_Homeworld_Avatar_Boy_Sword_Bennett (merge).fbx#Scene",
-    "models/Characters/Chongyun/NPC_Coop_Avatar_Boy_Claymore_Chongyun_Edit (merge).fbx#Scene",
-    "models/Characters/Collei/NPC_Avatar_Girl_Bow_Collei_Edit.fbx#Scene",
-    "models/Characters/Dainsleif/NPC_Avatar_Male_Sword_Dainslaif (merge).fbx#Scene",
-    "models/Characters/Dehya/NPC_Avatar_Lady_Claymore_Dehya.fbx#Scene",
-    "models/Characters/Diluc/Default/Cs_Avatar_Male_Claymore_Diluc.fbx#Scene",
-    "models/Characters/Diluc/Flamme/Avatar_Male_Claymore_DilucCostumeFlamme.fbx#Scene",
-    "models/Characters/Dori/NPC_Avatar_Loli_Claymore_Dori_Edit.fbx#Scene",
-    "models/Characters/Eula/NPC_Avatar_Lady_Claymore_Eula (merge).fbx#Scene",
-    "models/Characters/Faruzan/Avatar_Girl_Bow_Faruzan (merge).fbx#Scene",
-    "models/Characters/Fischl/Highness/Avatar_Girl_Bow_FischlCostumeHighness.fbx#Scene",
-    "models/Characters/Ganyu/Avatar_Girl_Bow_Ganyu (merge).fbx#Scene",
-    "models/Characters/Gorou/NPC_Avatar_Boy_Bow_Gorou_Edit (merge).fbx#Scene",
-    "models/Characters/Hu Tao/NPC_Avatar_Girl_Pole_Hutao (merge).fbx#Scene",
-    //huge mask "models/Characters/IlDotorre/NPC_Avatar_Male_Claymore_IlDotorre.fbx#Scene",
-    "models/Characters/Jean/Default/Cs_Avatar_Lady_Sword_Qin.fbx#Scene",
-    "models/Characters/Jean/Summer/NPC_Avatar_Lady_Sword_QinCostumeSea_Edit (merge).fbx#Scene",
-    "models/Characters/Kaedehara Kazuha/Cs_Avatar_Boy_Sword_Kazuha (merge).fbx#Scene",
-    "models/Characters/Kaeya/Cs_Avatar_Male_Sword_Kaeya.fbx#Scene",
-    "models/Characters/Kamisato Ayato/Avatar_Male_Sword_Ayato (merge).fbx#Scene",
-    "models/Characters/Keqing/Default/NPC_Avatar_Girl_Sword_Keqing (merge).fbx#Scene",
-    "models/Characters/Keqing/Feather/NPC_Avatar_Girl_Sword_KeqingCostumeFeather_Edit (merge).fbx#Scene",
-    "models/Characters/Klee/Cs_Avatar_Loli_Catalyst_Klee.fbx#Scene",
-    "models/Characters/Kujou Sara/NPC_Avatar_Lady_Bow_Sara_Edit (merge).fbx#Scene",
-    "models/Characters/Kuki Shinobu/NPC_Homeworld_Avatar_Girl_Sword_Shinobu (merge).fbx#Scene",
-    "models/Characters/Lisa/NPC_Avatar_Lady_Catalyst_Lisa_Edit (merge).fbx#Scene",
-    "models/Characters/Lumine/Cs_Avatar_Girl_Sword_PlayerGirl.fbx#Scene",
-    "models/Characters/Nahida/NPC_Avatar_Loli_Catalyst_Nahida_Edit.fbx#Scene",
-    "models/Characters/Nilou/NPC_Avatar_Girl_Sword_Nilou.fbx#Scene",
-    "models/Characters/Ningguang/Default/Cs_Avatar_Lady_Catalyst_Ningguang.fbx#Scene",
-    "models/Characters/Ningguang/Floral/Avatar_Lady_Catalyst_NingguangCostumeFloral (merge).fbx#Scene",
-    "models/Characters/Noelle/NPC_Coop_Avatar_Girl_Claymore_Noel (merge).fbx#Scene",
-    "models/Characters/Raiden Shogun/Cs_Avatar_Lady_Pole_Shougun (merge).fbx#Scene",
-    "models/Characters/Razor/NPC_Avatar_Boy_Claymore_Razor.fbx#Scene",
-    "models/Characters/Rosaria/Default/NPC_Avatar_Lady_Pole_Rosaria (merge).fbx#Scene",
-    "models/Characters/Sangonomiya Kokomi/NPC_Homeworld_Avatar_Girl_Catalyst_Kokomi_Edit (merge).fbx#Scene",
-    "models/Characters/Sayu/Avatar_Loli_Claymore_Sayu (merge).fbx#Scene",
-    "models/Characters/Shenhe/NPC_Avatar_Lady_Pole_Shenhe_Edit (merge).fbx#Scene",
-    "models/Characters/Shikanoin Heizou/NPC_Avatar_Boy_Catalyst_Heizo_Edit (merge).fbx#Scene",
-    "models/Characters/Sucrose/NPC_Homeworld_Avatar_Girl_Catalyst_Sucrose_Edit (merge).fbx#Scene",
-    "models/Characters/Tighnari/Avatar_Boy_Bow_Tighnari.fbx#Scene",
-    "models/Characters/Venti/NPC_Avatar_Boy_Bow_Venti.fbx#Scene",
-    "models/Characters/Wanderer/Avatar_Boy_Catalyst_Wanderer (merge).fbx#Scene",
-    "models/Characters/Xiangling/NPC_Avatar_Girl_Pole_Xiangling_Edit (merge).fbx#Scene",
-    "models/Characters/Xiao/Cs_Avatar_Boy_Pole_Xiao.fbx#Scene",
-    "models/Characters/Xingqiu/Avatar_Boy_Sword_Xingqiu_Remote (merge).fbx#Scene",
-    "models/Characters/Xinyan/NPC_Homeworld_Avatar_Girl_Claymore_Xinyan (merge).fbx#Scene",
-    "models/Characters/Yae Miko/Avatar_Lady_Catalyst_Yae_Remote (merge).fbx#Scene",
-    "models/Characters/Yanfei/NPC_Homeworld_Avatar_Girl_Catalyst_Feiyan_Edit (merge).fbx#Scene",
-    "models/Characters/Yelan/Cs_Avatar_Lady_Bow_Yelan (merge).fbx#Scene",
-    "models/Characters/Zhongli/NPC_Avatar_Male_Pole_Zhongli_Edit (merge).fbx#Scene"
-];
-
-//#[cfg(feature = "screenshot")]
-
-lazy_static! {
-    static ref HEIGHT_OFFSETS: HashMap<String, f32> = {
-        HashMap::from([
-            ("Lady".to_string(), 0.15),
-            ("Girl".to_string(), 0.0),
-            ("Loli".to_string(), -0.25),
-            ("Male".to_string(), 0.3),
-            ("Boy".to_string(), 0.09),
-        ])
-    };
-
-    static ref SIZE_OVERRIDES: HashMap<String, f32> = {
-        HashMap::from([
-            ("models/Characters/Kamisato Ayato/Avatar_Male_Sword_Ayato (merge).fbx#Scene".to_string(), 10.0),
-            ("models/Characters/Rosaria/Censored/Avatar_Lady_Pole_Rosaria.fbx#Scene".to_string(), 1.0),
-            //("models/Characters/Kaveh/NPC_Avatar_Male_Claymore_Kaveh.fbx#Scene".to_string(), 100.0),
-            //("models/Characters/Kamisato Ayato/Avatar_Male_Sword_Ayato (merge).fbx#Scene".to_string(), 1.0),
-        ])
-    };
-
-    static ref LIGHT_OFFSETS: HashMap<String, Vec3> = {
-        HashMap::from([
-            ("models/Characters/Arataki Itto/Avatar_Male_Claymore_Itto_Remote (merge).fbx#Scene".to_string(), Vec3::new(0.0, 0.0, -10.0)),
-            ("models/Characters/Kuki Shinobu/NPC_Homeworld_Avatar_Girl_Sword_Shinobu (merge).fbx#Scene".to_string(), Vec3::new(0.0, 0.0, -10.0)),
-            ("models/Characters/Kaveh/NPC_Avatar_Male_Claymore_Kaveh.fbx#Scene".to_string(), Vec3::new(0.0, 0.0, -10.0)),
-            ("models/Characters/Diluc/Default/Cs_Avatar_Male_Claymore_Diluc.fbx#Scene".to_string(), Vec3::new(0.0, 0.0, -10.0)),
-            ("models/Characters/Diluc/Flamme/Avatar_Male_Claymore_DilucCostumeFlamme.fbx#Scene".to_string(), Vec3::new(0.0, 0.0, -10.0)),
-        ])
-    };
-}
-
-struct GeneratePlugin;
-
-#[cfg(feature = "screenshot")]
-impl Plugin for GeneratePlugin {
-    fn build(&self, app: &mut App) {
-        app.add_system(screenshot_on_f12)
-            .add_system_set(SystemSet::on_update(AppState::Ready).with_system(generate));
-    }
-}
-
-#[cfg(not(feature = "screenshot"))]
-impl Plugin for GeneratePlugin {
-    fn build(&self, app: &mut App) {}
-}
-
 struct UiPlugin;
 
 #[cfg(feature = "ui")]
@@ -229,16 +128,32 @@ impl Plugin for UiPlugin {
 }
 
 fn main() {
-    App::new()
+    let cli = Cli::parse();
+
+    match cli.command.unwrap_or(Command::View) {
+        Command::View => run_app(None),
+        #[cfg(feature = "screenshot")]
+        Command::Generate(args) => run_app(Some(args)),
+        Command::ListCharacters => list_characters(),
+        Command::Validate(args) => {
+            if !validate(&args) {
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+fn run_app(generate: Option<GenerateArgs>) {
+    let headless = generate.as_ref().map(|g| g.headless).unwrap_or(false);
+    let mut app = App::new();
+    app
         //.insert_resource(ClearColor(Color::rgb(1.0, 1.0, 1.0)))
         .add_state(AppState::Loading)
         //.insert_resource(Msaa { samples: 4 })
-        .add_plugins(DefaultPlugins.set(AssetPlugin {
-            watch_for_changes: true,
-            ..Default::default()
-        }))
+        .add_plugins(default_plugins(headless))
         // .add_plugin(DebugLinesPlugin::default())
         .add_plugin(RonAssetPlugin::<PresetsGroups>::new(&["ron"]))
+        .add_plugin(RonAssetPlugin::<CharacterManifest>::new(&["manifest.ron"]))
 
         .insert_resource(FbxMaterialLoaders::<CelMaterial>(vec![
             &load_cel_material,
@@ -256,191 +171,234 @@ fn main() {
 
         .add_plugin(MaterialPlugin::<CelMaterial>::default())
         .add_plugin(CelShaderPlugin)
+        .add_plugin(OutlinePlugin)
+        .add_system(fix_color_spaces)
         .add_system(pan_orbit_camera)
-        .add_plugin(UiPlugin)
         .add_startup_system(setup)
         .add_system(initialize::<CelMaterial>)
-        .add_plugin(GeneratePlugin)
+        .add_plugin(FramingPlugin)
         .add_system(hotkey_system)
         .add_system(update_face_direction)
+        .add_system(sync_cel_lights)
+        .init_resource::<LightShadows>()
+        .add_system(sync_light_shadows)
+        .add_plugin(CapturePlugin);
         //.add_system(axis_lines)
         //.add_system(rotate_character_system)
-        .run();
-}
 
-fn rotate_character_system(
-    time: Res<Time>,
-    mut query: Query<(
-        &mut Transform,
-        With<CurrentCharacter>
-    )>,
-) {
-    for (mut transform, _) in query.iter_mut() {
-        transform.rotation = Quat::from_rotation_y(time.elapsed_seconds() as f32);
+    // egui needs the primary window
+    if !headless {
+        app.add_plugin(UiPlugin);
     }
-}
 
-fn update_face_direction(
-    mut query: Query<(
-        &mut Transform,
-        With<CurrentCharacter>
-    )>,
-    mut materials_query: Query<&mut Handle<CelMaterial>>,
-    mut materials: ResMut<Assets<CelMaterial>>,
-) {
-    if let Ok(trans) = query.get_single() {
-        let face_direction = trans.0.rotation;
-
-        materials_query.for_each_mut(|mat| {
-            if let Some(ref mut mat) = materials.get_mut(mat.as_ref()) {
-                mat.update_head_direction(face_direction.into())
+    if let Some(args) = generate {
+        let manifest = load_manifest();
+        let characters = cli::filter_characters(manifest.enabled(), &args.characters);
+        if characters.is_empty() {
+            eprintln!("no characters match {:?}", args.characters);
+            std::process::exit(1);
+        }
+        if let Err(e) = fs::create_dir_all(&args.output) {
+            eprintln!("cannot create {}: {:?}", args.output.display(), e);
+            std::process::exit(1);
+        }
+        let sample_log = match metadata::SampleLog::open(&args.output) {
+            Ok(log) => log,
+            Err(e) => {
+                eprintln!("cannot open the sample log: {:?}", e);
+                std::process::exit(1);
             }
-        });
+        };
+        let writer = output::SampleWriter::new(
+            args.layout,
+            args.output.clone(),
+            args.resolution,
+            args.downscale_filter,
+        );
+
+        let queue: Vec<_> = characters
+            .into_iter()
+            .map(|character| QueuedCharacter {
+                index: manifest
+                    .characters
+                    .iter()
+                    .position(|c| c.path == character.path)
+                    .unwrap(),
+                character: character.clone(),
+            })
+            .filter(|queued| {
+                // finished characters are not loaded at all
+                !args.skip_existing
+                    || (0..args.samples).any(|sample| {
+                        !writer.exists(&sample_name(&queued.character.path, sample), &args.all_passes())
+                    })
+            })
+            .collect();
+        if queue.is_empty() {
+            eprintln!("all samples already exist in {}", args.output.display());
+            return;
+        }
+
+        app.insert_resource(GenerateQueue(queue))
+        .insert_resource(sample_log)
+        .insert_resource(writer)
+        .insert_resource(FramingConfig {
+            target_height: args.target_height,
+            preset_head_height: args.preset_head_height,
+        })
+        .insert_resource(LightShadows(args.shadows))
+        // multisampling would average the packed values of the data passes at the edges,
+        // the colors are antialiased by supersampling instead
+        .insert_resource(Msaa { samples: 1 })
+        .insert_resource(args)
+        .add_plugin(GeneratePlugin);
     }
-}
 
-#[derive(PartialEq)]
-enum ScreenshotState {
-    SetupCamera,
-    ScreenhotA,
-    ScreenhotB,
-    Done
+    app.run();
 }
 
-impl Default for ScreenshotState {
-    fn default() -> Self {
-        Self::SetupCamera
+fn load_manifest() -> CharacterManifest {
+    match CharacterManifest::load_from_file(Path::new("assets").join(CHARACTERS_FILENAME)) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            eprintln!("cannot load the character manifest: {:?}", e);
+            std::process::exit(1);
+        }
     }
 }
 
-#[cfg(feature = "screenshot")]
-fn generate(
-    mut counter: Local<usize>,
-    mut skip_counter: Local<usize>,
-    mut character_counter: Local<usize>,
-    mut screenshot_manager: ResMut<ScreenshotManager>,
-    mut presets_handle: Option<ResMut<PresetsResource>>,
-    mut presets: ResMut<Assets<PresetsGroups>>,
-    mut camera_query: Query<(&mut Transform, With<Camera>)>,
-    mut light_query: Query<(&mut Transform, With<PointLight>, Without<Camera>)>,
-    mut materials_query: Query<&mut Handle<CelMaterial>>,
-    mut materials: ResMut<Assets<CelMaterial>>,
-    asset_server: Res<AssetServer>,
-    mut commands: Commands,
-    mut current_character_query: Query<(Entity, &mut Visibility), With<CurrentCharacter>>,
-    mut app_state: ResMut<State<AppState>>,
-    mut state: Local<ScreenshotState>
-) {
-    if *state == ScreenshotState::Done {
-        return;
-    }
+fn default_plugins(headless: bool) -> PluginGroupBuilder {
+    let plugins = DefaultPlugins.set(AssetPlugin {
+        watch_for_changes: true,
+        ..Default::default()
+    });
 
-    *skip_counter += 1;
-    if *skip_counter < 2 && *skip_counter % 2 == 0 {
-        return;
+    if headless {
+        plugins
+            .set(WindowPlugin {
+                add_primary_window: false,
+                exit_on_all_closed: false,
+                ..Default::default()
+            })
+            .disable::<WinitPlugin>()
+            .add(ScheduleRunnerPlugin)
+    } else {
+        plugins
     }
+}
 
-    let sample_size = 119;
+fn list_characters() {
+    for (i, character) in load_manifest().characters.iter().enumerate() {
+        if character.enabled {
+            println!("{:3} {}", i, character.path);
+        } else {
+            println!(
+                "{:3} {} (disabled: {})",
+                i,
+                character.path,
+                character.exclusion_reason.as_deref().unwrap_or("no reason given")
+            );
+        }
+    }
+}
 
-    assert!(sample_size & 1 == 1);
+/// Checks that every selected character model, its material folder and the preset group exist.
+/// Returns false if anything is missing.
+fn validate(args: &ValidateArgs) -> bool {
+    let mut ok = true;
 
-    if *counter > sample_size {
-        *character_counter += 1;
-        *counter = 0;
+    let manifest = match CharacterManifest::load_from_file(Path::new("assets").join(CHARACTERS_FILENAME)) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            eprintln!("{:?}", e);
+            return false;
+        }
+    };
 
-        if *character_counter == CHARACTERS.len() {
-            println!("done!");
-            *character_counter += 1;
-            return;
-        } else if *character_counter > CHARACTERS.len() {
-            return;
+    let material_paths = match MaterialPaths::load_from_file(Path::new("assets").join(MATERIAL_PATHS_FILENAME)) {
+        Ok(paths) => paths,
+        Err(e) => {
+            eprintln!("{:?}", e);
+            return false;
         }
+    };
 
-        //commands.entity(current_character_query.single()).despawn();
-        current_character_query
-            .for_each_mut(|mut c| c.1.set(Box::new(Visibility::Hidden)).unwrap());
-
-        app_state.as_mut().set(AppState::Loading).unwrap();
-        // commands.spawn((
-        //     SceneBundle {
-        //         scene: asset_server.load(CHARACTERS[*character_counter]),
-        //         //.load("models/Characters/Faruzan/Avatar_Girl_Bow_Faruzan (merge).fbx#Scene"),
-        //         transform: Transform::from_scale(Vec3::new(100.0, 100.0, 100.0)),
-        //         //scene: asset_server.load("models/cube.fbx#Scene"),//.load("models/fischl/Avatar_Girl_Bow_FischlCostumeHighness.fbx#Scene"),
-        //         ..default()
-        //     },
-        //     CurrentCharacter,
-        // ));
-        spawn_character(&mut commands, &asset_server, CHARACTERS[*character_counter]);
+    for character in cli::filter_characters(manifest.enabled(), &args.characters) {
+        let name = character.path.as_str();
+        let path = Path::new("assets").join(character.model_path());
+        if !path.is_file() {
+            eprintln!("missing model: {}", path.display());
+            ok = false;
+            continue;
+        }
 
-        return;
+        let material_dirs = material_paths.directories(Path::new(character.model_path()));
+        if !material_dirs.iter().any(|d| Path::new("assets").join(d).is_dir()) {
+            eprintln!("no material folder found for {}: {:?}", name, material_dirs);
+            ok = false;
+        }
     }
 
-    guard! { let Some(presets_handle) = presets_handle else { return; } }
-    if let Some(ref mut presets) = presets.get_mut(&presets_handle.0) {
-        guard! { let Some(presets) = presets.groups.get_mut("face2.5") else { return } }
-
-        //let change_state = *counter & 1 == 0;
-        match *state {
-            ScreenshotState::SetupCamera => {
-                if *character_counter >= CHARACTERS.len() {
-                    *state = ScreenshotState::Done;
-                    return;
-                }
-
-                let mut rng = rand::thread_rng();
-                let camera = rng.gen_range(0..presets.camera.len());
-                let camera = presets.camera[camera].clone();
-
-                let light = rng.gen_range(0..presets.light.len());
-                let mut light = presets.light[light].clone();
-                light.translation += LIGHT_OFFSETS.get(CHARACTERS[*character_counter]).map(|o| *o).unwrap_or(Vec3::ZERO);
-
-                let mut scene_camera = camera_query.single_mut().0;
-                *scene_camera.as_mut() = camera;
-                scene_camera.translation.y += get_height_offset(CHARACTERS[*character_counter]);
-
-                *light_query.single_mut().0.as_mut() = light;
+    if let Err(e) = TextureRules::load_from_file(Path::new("assets").join(TEXTURE_RULES_FILENAME)) {
+        eprintln!("{:?}", e);
+        ok = false;
+    }
 
-                *state = ScreenshotState::ScreenhotA;
+    let presets = fs::read_to_string(format!("assets/{}", PRESETS_FILENAME))
+        .map_err(anyhow::Error::from)
+        .and_then(|s| ron::from_str::<PresetsGroups>(&s).map_err(anyhow::Error::from));
+    match presets {
+        Ok(presets) => match presets.groups.get(&args.preset_group) {
+            Some(group) if group.camera.is_empty() || group.light.is_empty() => {
+                eprintln!("preset group {:?} has no cameras or lights", args.preset_group);
+                ok = false;
             }
-            ScreenshotState::ScreenhotA | ScreenshotState::ScreenhotB => {
-                materials_query.for_each_mut(|mat| {
-                    if let Some(ref mut mat) = materials.get_mut(mat.as_ref()) {
-                        mat.diffuse_only = *state == ScreenshotState::ScreenhotA;
-                    }
-                });
-
-                let path = format!("./output/sample-{}-{}.png", *character_counter, *counter);
-                *counter += 1;
-                screenshot_manager
-                    .save_screenshot_to_disk(WindowId::primary(), path)
-                    .unwrap();
-
-                if *state == ScreenshotState::ScreenhotA {
-                    *state = ScreenshotState::ScreenhotB;
-                } else {
-                    *state = ScreenshotState::SetupCamera;
-                }
+            Some(_) => {}
+            None => {
+                eprintln!("preset group {:?} not found in {}", args.preset_group, PRESETS_FILENAME);
+                ok = false;
             }
-            ScreenshotState::Done => {}
+        },
+        Err(e) => {
+            eprintln!("cannot read {}: {:?}", PRESETS_FILENAME, e);
+            ok = false;
         }
     }
+
+    if ok {
+        eprintln!("ok");
+    }
+    ok
 }
 
-#[cfg(feature = "screenshot")]
-fn screenshot_on_f12(
-    input: Res<Input<KeyCode>>,
-    //main_window: Query<Entity, With<PrimaryWindow>>,
-    mut screenshot_manager: ResMut<ScreenshotManager>,
-    mut counter: Local<u32>,
+fn rotate_character_system(
+    time: Res<Time>,
+    mut query: Query<(
+        &mut Transform,
+        With<CurrentCharacter>
+    )>,
 ) {
-    if input.just_pressed(KeyCode::F12) {
-        let path = format!("./screenshot-{}.png", *counter);
-        *counter += 1;
-        screenshot_manager
-            .save_screenshot_to_disk(WindowId::primary(), path)
-            .unwrap();
+    for (mut transform, _) in query.iter_mut() {
+        transform.rotation = Quat::from_rotation_y(time.elapsed_seconds() as f32);
+    }
+}
+
+fn update_face_direction(
+    mut query: Query<(
+        &mut Transform,
+        With<CurrentCharacter>
+    )>,
+    mut materials_query: Query<&mut Handle<CelMaterial>>,
+    mut materials: ResMut<Assets<CelMaterial>>,
+) {
+    if let Ok(trans) = query.get_single() {
+        let face_direction = trans.0.rotation;
+
+        materials_query.for_each_mut(|mat| {
+            if let Some(ref mut mat) = materials.get_mut(mat.as_ref()) {
+                mat.update_head_direction(face_direction.into())
+            }
+        });
     }
 }
 
@@ -451,6 +409,7 @@ fn hotkey_system(
     mut current_character_query: Query<Entity, With<CurrentCharacter>>,
     mut materials_query: Query<&mut Handle<CelMaterial>>,
     mut materials: ResMut<Assets<CelMaterial>>,
+    mut shadows: ResMut<LightShadows>,
     mut is_diffuse_only: Local<bool>
 ) {
     if input.just_pressed(KeyCode::D) {
@@ -459,6 +418,10 @@ fn hotkey_system(
         }
     }
 
+    if input.just_pressed(KeyCode::S) {
+        shadows.0 = !shadows.0;
+    }
+
     if input.just_pressed(KeyCode::F) {
         *is_diffuse_only = !*is_diffuse_only;
         materials_query.for_each_mut(|mat| {
@@ -469,14 +432,6 @@ fn hotkey_system(
     }
 }
 
-fn get_height_offset(name: &str) -> f32 {
-    HEIGHT_OFFSETS
-        .iter()
-        .find(|o| name.contains(o.0))
-        .map(|o| *o.1)
-        .unwrap_or(0.0)
-}
-
 #[derive(Default)]
 struct UIState {
     hidden: bool,
@@ -493,8 +448,13 @@ fn ui_system(
     mut presets_handle: Option<ResMut<PresetsResource>>,
     mut presets_groups: ResMut<Assets<PresetsGroups>>,
     mut camera_query: Query<(&mut Transform, With<Camera>)>,
-    mut light_query: Query<(&mut Transform, With<PointLight>, Without<Camera>)>,
-    mut current_character_query: Query<(Entity, &mut Visibility, &Name), With<CurrentCharacter>>,
+    mut light_query: Query<(&mut Transform, &lights::SceneLight), (With<lights::MainLight>, Without<Camera>)>,
+    mut current_character_query: Query<
+        (Entity, &mut Visibility, &Name, Option<&CharacterFraming>),
+        With<CurrentCharacter>,
+    >,
+    manifest_handle: Option<Res<CharacterManifestResource>>,
+    manifests: Res<Assets<CharacterManifest>>,
     mut state: Local<UIState>,
     mut current_presets: Local<Presets>,
     input: Res<Input<KeyCode>>,
@@ -552,8 +512,7 @@ fn ui_system(
                 {
                     *camera_query.single_mut().0.as_mut() = (*camera).clone();
                     camera_query.single_mut().0.translation.y +=
-                        get_height_offset(selected.2.as_str()); //state.offset;
-                                                                //println!("offset {}", get_height_offset(selected.2.as_str()));
+                        selected.3.map(|f| f.camera_offset).unwrap_or(0.0); //state.offset;
                 }
             }
             if ui.button("Add").clicked() {
@@ -561,7 +520,7 @@ fn ui_system(
             }
         });
 
-    let mut light = light_query.single_mut().0;
+    let (mut light, scene_light) = light_query.single_mut();
 
     egui::Window::new("Move light").show(egui_context.ctx_mut(), |ui| {
         ui.horizontal(|ui| {
@@ -582,10 +541,19 @@ fn ui_system(
         .vscroll(true)
         .show(egui_context.ctx_mut(), |ui| {
             for light in &presets.light {
-                ui.label(format!("{:?}", light.translation));
+                let light = light.preset();
+                ui.label(format!("{:?} {:?}", light.main.kind, light.main.transform.translation));
             }
             if ui.button("Add").clicked() {
-                presets.light.push(light.clone());
+                presets.light.push(LightPresetEntry::Lights(lights::LightPreset {
+                    main: LightSettings {
+                        kind: scene_light.kind,
+                        transform: light.clone(),
+                        color: scene_light.color,
+                        intensity: scene_light.intensity,
+                    },
+                    fill: None,
+                }));
             }
         });
 
@@ -634,20 +602,23 @@ fn ui_system(
         egui::ScrollArea::vertical().show(ui, |ui| {
             ui.label(format!("selected: {:?}", selected.2.as_str()));
             //ui.label(format!("loaded characters: {:?}", current_character_query.iter().count()));
-            for name in CHARACTERS {
-                if !name
+            let manifest = manifest_handle.as_ref().and_then(|h| manifests.get(&h.0));
+            guard! { let Some(manifest) = manifest else { return; } }
+            for character in manifest.enabled() {
+                if !character
+                    .path
                     .to_ascii_lowercase()
                     .contains(state.filter.to_ascii_lowercase().as_str())
                 {
                     continue;
                 }
                 if ui
-                    .add(Label::new(format!("{:?}", name)).sense(Sense::click()))
+                    .add(Label::new(format!("{:?}", character.path)).sense(Sense::click()))
                     .clicked()
                 {
                     selected.1.is_visible = false;
                     //commands.entity(selected.0).despawn();
-                    spawn_character(&mut commands, &asset_server, name)
+                    spawn_character(&mut commands, &asset_server, character)
                 }
             }
         });
@@ -698,8 +669,12 @@ fn initialize<M: bevy::prelude::Material>(
                         vis.is_visible = false;
                     }
 
-                if *app_state.as_ref().current() != AppState::Ready {
-                    app_state.as_mut().set(AppState::Ready).unwrap();
+                // a late scene must not leave the failed state before the next character is spawned
+                if *app_state.as_ref().current() == AppState::Loading {
+                    // a failure queued this frame wins over the late scene
+                    if let Err(e) = app_state.as_mut().set(AppState::Ready) {
+                        debug!("Not switching to Ready: {:?}", e);
+                    }
                 }
             }
             AssetEvent::Modified { handle: _ } => {
@@ -712,19 +687,23 @@ fn initialize<M: bevy::prelude::Material>(
     }
 }
 
-fn spawn_character(commands: &mut Commands, asset_server: &Res<AssetServer>, name: &str) {
-    let size = SIZE_OVERRIDES.get(name).map(|v| *v).unwrap_or(100.0);
+fn spawn_character(
+    commands: &mut Commands,
+    asset_server: &Res<AssetServer>,
+    character: &CharacterEntry,
+) {
+    let size = character.scale();
 
     commands.spawn((
         SceneBundle {
-            scene: asset_server.load(name), //"models/Characters/Faruzan/Avatar_Girl_Bow_Faruzan (merge).fbx#Scene"),
+            scene: asset_server.load(character.path.as_str()), //"models/Characters/Faruzan/Avatar_Girl_Bow_Faruzan (merge).fbx#Scene"),
             //.load("models/Characters/Faruzan/Avatar_Girl_Bow_Faruzan (merge).fbx#Scene"),
             transform: Transform::from_scale(Vec3::new(size, size, size)),
             //scene: asset_server.load("models/cube.fbx#Scene"),//.load("models/fischl/Avatar_Girl_Bow_FischlCostumeHighness.fbx#Scene"),
             ..default()
         },
-        CurrentCharacter,
-        Name::new(name.to_string()),
+        CurrentCharacter(character.clone()),
+        Name::new(character.path.clone()),
     ));
 }
 
@@ -736,10 +715,27 @@ fn setup(
     mut materials: ResMut<Assets<StandardMaterial>>,
     _custom_materials: ResMut<Assets<CelMaterial>>,
     mut windows: ResMut<Windows>,
+    mut images: ResMut<Assets<Image>>,
+    generate_config: Option<Res<GenerateArgs>>,
+    generate_queue: Option<Res<GenerateQueue>>,
+    shadows: Res<LightShadows>,
 ) {
-    let window = windows.get_primary_mut().unwrap();
-    println!("Window size was: {},{}", window.width(), window.height());
-    window.set_resolution(512.0, 512.0);
+    if let Some(window) = windows.get_primary_mut() {
+        println!("Window size was: {},{}", window.width(), window.height());
+        window.set_resolution(512.0, 512.0);
+    }
+
+    // dataset samples are rendered offscreen so their size does not depend on the window
+    let capture_target = generate_config.map(|config| {
+        let size = config.resolution * config.supersample.max(1);
+        CaptureTarget {
+            color: capture::create_target(&mut images, size, size, TextureFormat::Rgba8UnormSrgb),
+            data: capture::create_target(&mut images, size, size, TextureFormat::Rgba8Unorm),
+        }
+    });
+    if let Some(target) = &capture_target {
+        commands.insert_resource(target.clone());
+    }
     // plane
     // commands.spawn(MaterialMeshBundle {
     //     mesh: meshes.add(Mesh::from(shape::Plane { size: 5.0 })),
@@ -766,29 +762,33 @@ fn setup(
     // });
 
     // light
-    commands.spawn(PointLightBundle {
-        point_light: PointLight {
-            intensity: 1500.0,
-            shadows_enabled: false,
-            ..default()
-        },
-        transform: Transform::from_xyz(4.0, 8.0, 4.0),
-        ..default()
-    });
+    spawn_light(
+        &mut commands,
+        &LightSettings::point(Transform::from_xyz(4.0, 8.0, 4.0)),
+        true,
+        *shadows,
+    );
     // camera
     // commands.spawn(Camera3dBundle {
     //     transform: Transform::from_xyz(-0.5, 1.5, 1.0)
     //         .looking_at(Vec3::new(0.0, 1.0, 0.0), Vec3::Y),
     //     ..default()
     // });
-    spawn_camera(&mut commands);
-
-    #[cfg(feature = "screenshot")]
-    let character = CHARACTERS[0];
-    #[cfg(feature = "ui")]
-    let character = "models/Characters/Amber/Default/Avatar_Girl_Bow_Ambor.fbx#Scene";
+    spawn_camera(&mut commands, capture_target.map(|target| target.color));
+
+    let character = generate_queue
+        .as_ref()
+        .map(|q| q.0[0].character.clone())
+        .unwrap_or_else(|| {
+            CharacterEntry::from_path(
+                "models/Characters/Amber/Default/Avatar_Girl_Bow_Ambor.fbx#Scene",
+            )
+        });
 
-    spawn_character(&mut commands, &asset_server, character);
+    spawn_character(&mut commands, &asset_server, &character);
 
     commands.insert_resource(PresetsResource(asset_server.load(PRESETS_FILENAME)));
+    commands.insert_resource(CharacterManifestResource(
+        asset_server.load(CHARACTERS_FILENAME),
+    ));
 }
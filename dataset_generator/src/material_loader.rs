@@ -1,10 +1,17 @@
+use std::collections::BTreeMap;
 use std::path::Path;
 
-use crate::{cel_material::CelMaterial, material_properties_types::MaterialPropertiesRoot};
-use anyhow::Context;
+use crate::failures::{material_report, record_material_report};
+use crate::material_paths::{
+    find_material_json, load_texture_index, MaterialPaths, MATERIAL_PATHS_FILENAME,
+};
+use crate::texture_rules::{TextureNames, TextureRules, TEXTURE_RULES_FILENAME};
+use crate::cel_material::{cull_mode_from_floats, CelAlphaMode, CelMaterial};
+use crate::material_properties_types::{MTexEnvs, MaterialPropertiesRoot};
+use crate::outline_material::OutlineProperties;
 use bevy::{
-    asset::LoadedAsset, prelude::*, reflect::GetPath, render::texture::ImageType,
-    utils::BoxedFuture,
+    asset::LoadedAsset, prelude::*, reflect::GetPath, render::render_resource::TextureFormat,
+    render::texture::ImageType, utils::BoxedFuture, utils::HashMap,
 };
 use bevy_mod_fbx::material_loader::TextureLoader;
 use bevy_mod_fbx::utils::fbx_extend::MaterialHandleExt;
@@ -12,6 +19,117 @@ use fbxcel_dom::v7400::object::{
     material::MaterialHandle, texture::TextureHandle, TypedObjectHandle,
 };
 
+/// Texture slots of the cel material.
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize,
+)]
+// snake case so the names are valid identifiers in the RON texture rules
+#[serde(rename_all = "snake_case")]
+pub enum MapKind {
+    Diffuse,
+    Lightmap,
+    FaceLightmap,
+    FaceShadow,
+    ShadowRamp,
+    MetalMap,
+    Normalmap,
+}
+
+impl MapKind {
+    /// Color maps are stored in sRGB, data maps hold linear values and must not be decoded.
+    pub fn is_srgb(&self) -> bool {
+        match self {
+            MapKind::Diffuse | MapKind::ShadowRamp => true,
+            // the metal map is a matcap used as a specular intensity, not a color
+            MapKind::Lightmap
+            | MapKind::FaceLightmap
+            | MapKind::FaceShadow
+            | MapKind::MetalMap
+            | MapKind::Normalmap => false,
+        }
+    }
+}
+
+/// Same format with the color space of the map, `None` for formats without an sRGB variant.
+pub fn with_color_space(format: TextureFormat, srgb: bool) -> Option<TextureFormat> {
+    use TextureFormat::*;
+    let (linear, srgb_format) = match format {
+        Rgba8Unorm | Rgba8UnormSrgb => (Rgba8Unorm, Rgba8UnormSrgb),
+        Bgra8Unorm | Bgra8UnormSrgb => (Bgra8Unorm, Bgra8UnormSrgb),
+        Bc1RgbaUnorm | Bc1RgbaUnormSrgb => (Bc1RgbaUnorm, Bc1RgbaUnormSrgb),
+        Bc2RgbaUnorm | Bc2RgbaUnormSrgb => (Bc2RgbaUnorm, Bc2RgbaUnormSrgb),
+        Bc3RgbaUnorm | Bc3RgbaUnormSrgb => (Bc3RgbaUnorm, Bc3RgbaUnormSrgb),
+        Bc7RgbaUnorm | Bc7RgbaUnormSrgb => (Bc7RgbaUnorm, Bc7RgbaUnormSrgb),
+        _ => return None,
+    };
+    Some(if srgb { srgb_format } else { linear })
+}
+
+/// Where a map of the material was loaded from.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "source", rename_all = "kebab-case")]
+pub enum MapSource {
+    /// Texture object of the FBX
+    Fbx { texture: String },
+    /// File in the model folder
+    Folder { file: String },
+}
+
+/// A map of the material and the rule that found it.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ResolvedMap {
+    /// Texture rule pattern, not set for the diffuse texture referenced by the FBX material
+    pub pattern: Option<String>,
+    #[serde(flatten)]
+    pub source: MapSource,
+}
+
+/// Why a cel material could not be created, the fallback material is used instead.
+#[derive(thiserror::Error, Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum MaterialLoadError {
+    /// Effect meshes have no diffuse texture and use the fallback on purpose
+    #[error("no diffuse texture")]
+    MissingDiffuse,
+    #[error("diffuse texture has no name")]
+    UnnamedDiffuse,
+    #[error("{map:?} map not found in the fbx or the model folder, tried {tried:?}")]
+    MissingMap { map: MapKind, tried: Vec<String> },
+    #[error("cannot get the properties file name from {name:?}")]
+    InvalidMaterialName { name: String },
+    #[error("material properties not found, tried {tried:?}")]
+    MissingMaterialJson { tried: Vec<String> },
+    #[error("cannot read material properties {path}: {error}")]
+    InvalidMaterialJson { path: String, error: String },
+    #[error("cannot load {file}: {error}")]
+    InvalidConfig { file: String, error: String },
+}
+
+/// Problems that do not prevent the cel material from being created.
+#[derive(thiserror::Error, Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum MaterialLoadWarning {
+    #[error("optional {map:?} map not found, tried {tried:?}")]
+    MissingOptionalMap { map: MapKind, tried: Vec<String> },
+    #[error("cannot load texture {texture}: {error}")]
+    TextureLoadFailed { texture: String, error: String },
+    #[error("cannot read texture list {file}: {error}")]
+    InvalidTextureList { file: String, error: String },
+}
+
+/// Outcome of loading one FBX material, kept on the material and in the failure registry.
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct MaterialLoadReport {
+    pub material: String,
+    /// Material properties JSON
+    pub properties: Option<String>,
+    pub maps: BTreeMap<MapKind, ResolvedMap>,
+    pub missing: Vec<MapKind>,
+    pub warnings: Vec<MaterialLoadWarning>,
+    /// Set when the fallback material is used
+    pub error: Option<MaterialLoadError>,
+}
+
 fn find_texture<'a>(material_obj: &MaterialHandle<'a>, name: &str) -> Option<TextureHandle<'a>> {
     material_obj
         .document()
@@ -23,29 +141,98 @@ fn find_texture<'a>(material_obj: &MaterialHandle<'a>, name: &str) -> Option<Tex
         .find(|handle| handle.name().unwrap_or("").contains(name))
 }
 
+/// Looks for the texture in the FBX first and in the model folder second.
+/// Textures from the FBX get their color space in `fix_color_spaces`, the loader only has their handles.
 async fn load_texture<'a, 'w>(
     texture_loader: &mut TextureLoader<'a, 'w>,
     material_obj: &MaterialHandle<'a>,
-    tokens: &Vec<&str>,
-    sub_name_ind: usize,
-    sub_name: &str,
-) -> anyhow::Result<Option<Handle<Image>>> {
-    let name = format!("{}_{}", tokens[..sub_name_ind].join("_"), sub_name);
-    let texture = find_texture(material_obj, &name);
-
-    if let Some(texture) = texture {
-        let tex = texture_loader
-            .get_cached_texture(texture)
-            .await
-            .map(|h| Some(h));
-
-        if tex.is_ok() {
-            return tex;
+    report: &mut MaterialLoadReport,
+    map: MapKind,
+    name: &str,
+) -> Option<(Handle<Image>, MapSource)> {
+    if let Some(texture) = find_texture(material_obj, name) {
+        match texture_loader.get_cached_texture(texture).await {
+            Ok(handle) => {
+                let source = MapSource::Fbx {
+                    texture: name.to_string(),
+                };
+                return Some((handle, source));
+            }
+            Err(e) => report.warnings.push(MaterialLoadWarning::TextureLoadFailed {
+                texture: name.to_string(),
+                error: format!("{:?}", e),
+            }),
         }
     }
 
-    println!("{} not found in fbx, trying to find in folder", &name);
+    debug!("{} not found in fbx, trying to find in folder", name);
 
+    match load_texture_from_folder(texture_loader, name, map.is_srgb()).await {
+        Ok(Some((handle, file))) => Some((handle, MapSource::Folder { file })),
+        Ok(None) => None,
+        Err(e) => {
+            report.warnings.push(MaterialLoadWarning::TextureLoadFailed {
+                texture: name.to_string(),
+                error: format!("{:?}", e),
+            });
+            None
+        }
+    }
+}
+
+/// Name of the texture the material JSON binds to the map, if any and if it is in the texture index.
+fn tex_env_texture<'a>(
+    tex_envs: &MTexEnvs,
+    texture_index: &'a HashMap<i64, String>,
+    map: MapKind,
+) -> Option<&'a str> {
+    let texture = match map {
+        MapKind::Diffuse => &tex_envs.main_tex,
+        MapKind::Lightmap => &tex_envs.light_map_tex,
+        MapKind::FaceLightmap => &tex_envs.face_map_tex,
+        MapKind::ShadowRamp => &tex_envs.packed_shadow_ramp_tex,
+        MapKind::MetalMap => &tex_envs.mtmap,
+        MapKind::Normalmap => &tex_envs.bump_map,
+        MapKind::FaceShadow => return None,
+    };
+    texture_index.get(&texture.path_id()?).map(|name| name.as_str())
+}
+
+/// Tries the texture rules of the map in order, returns the names that were tried if none matches.
+async fn resolve_map<'a, 'w>(
+    texture_loader: &mut TextureLoader<'a, 'w>,
+    material_obj: &MaterialHandle<'a>,
+    report: &mut MaterialLoadReport,
+    rules: &TextureRules,
+    names: &TextureNames<'_>,
+    tex_env: Option<&str>,
+    map: MapKind,
+) -> Result<Handle<Image>, Vec<String>> {
+    let mut tried = Vec::new();
+    for pattern in rules.patterns(names.is_face, map) {
+        // the diffuse name has fewer tokens than the pattern needs, or the map is not bound in the material JSON
+        guard! { let Some(name) = names.expand(pattern, tex_env) else { continue } }
+
+        if let Some((handle, source)) = load_texture(texture_loader, material_obj, report, map, &name).await {
+            let resolved = ResolvedMap {
+                pattern: Some(pattern.clone()),
+                source,
+            };
+            report.maps.insert(map, resolved);
+            return Ok(handle);
+        }
+        tried.push(name);
+    }
+
+    report.missing.push(map);
+    Err(tried)
+}
+
+async fn load_texture_from_folder<'a, 'w>(
+    texture_loader: &mut TextureLoader<'a, 'w>,
+    name: &str,
+    is_srgb: bool,
+) -> anyhow::Result<Option<(Handle<Image>, String)>> {
     let parent = texture_loader.load_context.path().parent().unwrap();
     let name = format!("{name}.png");
     let file = texture_loader
@@ -54,15 +241,7 @@ async fn load_texture<'a, 'w>(
         .read_directory(parent)?
         .find(|f| f.ends_with(&name));
 
-    guard! { let Some(file) = file else {
-        println!("{} not found in folder as well", name);
-        texture_loader.load_context
-        .asset_io()
-        .read_directory(texture_loader.load_context.path().parent().unwrap())?
-        .for_each(|f| println!("{:?}", f));
-
-        return Ok(None)
-    } }
+    guard! { let Some(file) = file else { return Ok(None) } }
 
     let image_path = Path::new(&file); //parent.join(&file);
     let image = texture_loader
@@ -77,7 +256,6 @@ async fn load_texture<'a, 'w>(
         .unwrap()
         .to_ascii_lowercase();
 
-    let is_srgb = false; // TODO
     let image = Image::from_buffer(
         &image,
         ImageType::Extension(&file_ext),
@@ -89,7 +267,7 @@ async fn load_texture<'a, 'w>(
         .load_context
         .set_labeled_asset(&name, LoadedAsset::new(image));
 
-    Ok(Some(handle))
+    Ok(Some((handle, file.display().to_string())))
 }
 
 //Avatar_Girl_Catalyst_BarbaraCostumeSummertime_Tex_Body_Shadow_Ramp.png
@@ -100,114 +278,231 @@ pub fn load_cel_material<'a, 'w>(
     material_obj: MaterialHandle<'a>,
 ) -> BoxedFuture<'a, anyhow::Result<Option<CelMaterial>>> {
     Box::pin(async move {
-        println!("start loading {:?}", material_obj.name());
-
-        let diffuse = material_obj.find_texture("DiffuseColor");
-        guard! { let Some(diffuse) = diffuse else {
-            println!("diffuse property not found");
-            return Ok(None)
-        } };
-        let name = diffuse.name();
-        guard! { let Some(name) = name else {
-            println!("cannot get name of diffuse property");
-            return Ok(None)
-        } };
-
-        let is_face = name.contains("Tex_Face");
-        let tokens = name.split('_').collect();
-
-        macro_rules! load_optional_map {
-            ($name: literal, $ind: expr) => {
-                load_texture(texture_loader, &material_obj, &tokens, $ind, $name)
-                    .await
-                    .inspect_err(|e| println!("Error while loading tex {:?}", e))
-                    .ok()
-                    .flatten()
-                //.context(concat!("Unable to load fbx map ", $name))?
-            };
-        }
+        let model = texture_loader.load_context.path().to_path_buf();
+        let mut report = MaterialLoadReport {
+            material: material_obj.name().unwrap_or("").to_string(),
+            ..default()
+        };
 
-        macro_rules! load_map {
-            ($name: literal, $ind: expr) => {
-                if let Some(map) = load_optional_map!($name, $ind) {
-                    map
+        let result = try_load_cel_material(texture_loader, &material_obj, &mut report).await;
+
+        for warning in &report.warnings {
+            warn!("{}: {}", report.material, warning);
+        }
+        let material = match result {
+            Ok(material) => {
+                debug!("loaded {}: {:?}", report.material, report.maps);
+                Some(material)
+            }
+            Err(error) => {
+                if error == MaterialLoadError::MissingDiffuse {
+                    debug!("{}: {}", report.material, error);
                 } else {
-                    println!("{} map not found", $name);
-                    return Ok(None);
+                    error!("{}: {}", report.material, error);
                 }
-            };
-        }
-
-        println!("{:?}", texture_loader.load_context.path());
-
-        let diffuse = {
-            let diffuse = texture_loader.get_cached_texture(diffuse).await;
-            if let Ok(diffuse) = diffuse {
-                diffuse
-            } else {
-                load_map!("Diffuse", 6)
+                report.error = Some(error);
+                None
             }
         };
 
-        let parent = texture_loader.load_context.path().parent().unwrap();
-
-        let single_model = texture_loader
-            .load_context
-            .asset_io()
-            .is_dir(parent.join("Materials").as_path());
-        let mat_name = material_obj.name().and_then(|m| m.split(".").next());
-        guard! { let Some(mat_name) = mat_name else {
-            println!("Cannot parse material name {:?}", material_obj.name());
-            return Ok(None)
-        } };
-
-        let path = if single_model {
-            format!("Materials/{}.json", mat_name)
-        } else {
-            format!("../Materials/{}.json", mat_name)
-        };
+        record_material_report(&model, report.clone());
 
-        let path = parent.join(path);
+        Ok(material.map(|mut material| {
+            material.load_report = report;
+            material
+        }))
+    })
+}
 
-        if !texture_loader.load_context.asset_io().is_file(&path) {
-            println!("Cannot load material json properties");
-            return Ok(None);
-        }
+async fn try_load_cel_material<'a, 'w>(
+    texture_loader: &mut TextureLoader<'a, 'w>,
+    material_obj: &MaterialHandle<'a>,
+    report: &mut MaterialLoadReport,
+) -> Result<CelMaterial, MaterialLoadError> {
+    let diffuse = material_obj.find_texture("DiffuseColor");
+    guard! { let Some(diffuse) = diffuse else {
+        return Err(MaterialLoadError::MissingDiffuse)
+    } };
+    let name = diffuse.name();
+    guard! { let Some(name) = name else {
+        return Err(MaterialLoadError::UnnamedDiffuse)
+    } };
 
-        let properties = texture_loader.load_context.read_asset_bytes(path).await?;
-        let properties: MaterialPropertiesRoot = serde_json::from_slice(properties.as_slice())?;
-
-        if is_face {
-            Ok(Some(CelMaterial::new_face(
-                diffuse,
-                load_map!("Tex_FaceLightmap", 2),
-                load_map!("Tex_Face_Shadow", 1),
-                load_map!("Tex_MetalMap", 1),
-                load_optional_map!("Shadow_Ramp", 5),
-                properties.into(),
-            )))
-        } else {
-            Ok(Some(CelMaterial::new(
-                diffuse,
-                load_map!("Lightmap", 6),
-                load_map!("Shadow_Ramp", 6),
-                load_map!("Tex_MetalMap", 1),
-                load_optional_map!("Normalmap", 6),
-                properties.into(),
-            )))
+    let mat_name = material_obj.name().and_then(|m| m.split(".").next());
+    guard! { let Some(mat_name) = mat_name else {
+        return Err(MaterialLoadError::InvalidMaterialName {
+            name: material_obj.name().unwrap_or("").to_string(),
+        })
+    } };
+
+    let invalid_config = |file: &str, error: anyhow::Error| MaterialLoadError::InvalidConfig {
+        file: file.to_string(),
+        error: format!("{:#}", error),
+    };
+    let rules = TextureRules::load(&texture_loader.load_context)
+        .await
+        .map_err(|e| invalid_config(TEXTURE_RULES_FILENAME, e))?;
+    let material_paths = MaterialPaths::load(&texture_loader.load_context)
+        .await
+        .map_err(|e| invalid_config(MATERIAL_PATHS_FILENAME, e))?;
+    let names = TextureNames::new(name, mat_name, &rules);
+
+    let path = find_material_json(&texture_loader.load_context, &material_paths, mat_name)
+        .await
+        .map_err(|tried| MaterialLoadError::MissingMaterialJson { tried })?;
+    report.properties = Some(path.display().to_string());
+
+    let invalid_json = |error: String| MaterialLoadError::InvalidMaterialJson {
+        path: path.display().to_string(),
+        error,
+    };
+    let properties = texture_loader
+        .load_context
+        .read_asset_bytes(&path)
+        .await
+        .map_err(|e| invalid_json(e.to_string()))?;
+    let properties: MaterialPropertiesRoot =
+        serde_json::from_slice(properties.as_slice()).map_err(|e| invalid_json(e.to_string()))?;
+
+    let tex_envs = &properties.m_saved_properties.m_tex_envs;
+    let (texture_index, list_errors) =
+        load_texture_index(&texture_loader.load_context, &material_paths).await;
+    for (file, error) in list_errors {
+        report
+            .warnings
+            .push(MaterialLoadWarning::InvalidTextureList { file, error });
+    }
+
+    macro_rules! load_optional_map {
+        ($map: expr) => {{
+            let tex_env = tex_env_texture(tex_envs, &texture_index, $map);
+            match resolve_map(texture_loader, material_obj, report, &rules, &names, tex_env, $map).await {
+                Ok(handle) => Some(handle),
+                Err(tried) => {
+                    report
+                        .warnings
+                        .push(MaterialLoadWarning::MissingOptionalMap { map: $map, tried });
+                    None
+                }
+            }
+        }};
+    }
+
+    macro_rules! load_map {
+        ($map: expr) => {{
+            let tex_env = tex_env_texture(tex_envs, &texture_index, $map);
+            match resolve_map(texture_loader, material_obj, report, &rules, &names, tex_env, $map).await {
+                Ok(handle) => handle,
+                Err(tried) => return Err(MaterialLoadError::MissingMap { map: $map, tried }),
+            }
+        }};
+    }
+
+    let diffuse = match texture_loader.get_cached_texture(diffuse).await {
+        Ok(diffuse) => {
+            let resolved = ResolvedMap {
+                pattern: None,
+                source: MapSource::Fbx {
+                    texture: name.to_string(),
+                },
+            };
+            report.maps.insert(MapKind::Diffuse, resolved);
+            diffuse
         }
-    })
+        Err(_) => load_map!(MapKind::Diffuse),
+    };
+
+    let outline = match properties.m_saved_properties.m_floats.outline_type {
+        Some(outline_type) if outline_type == 0.0 => None,
+        _ => Some(OutlineProperties::from(&properties)),
+    };
+    let (alpha_mode, _) = CelAlphaMode::from_floats(&properties.m_saved_properties.m_floats);
+    let cull_mode = cull_mode_from_floats(&properties.m_saved_properties.m_floats);
+    let mut material = if names.is_face {
+        CelMaterial::new_face(
+            diffuse,
+            load_map!(MapKind::FaceLightmap),
+            load_map!(MapKind::FaceShadow),
+            load_map!(MapKind::MetalMap),
+            load_optional_map!(MapKind::ShadowRamp),
+            properties.into(),
+        )
+    } else {
+        CelMaterial::new(
+            diffuse,
+            load_map!(MapKind::Lightmap),
+            load_map!(MapKind::ShadowRamp),
+            load_map!(MapKind::MetalMap),
+            load_optional_map!(MapKind::Normalmap),
+            properties.into(),
+        )
+    };
+    material.outline = outline;
+    material.alpha_mode = alpha_mode;
+    material.cull_mode = cull_mode;
+    Ok(material)
 }
 
 pub fn load_cel_material_fallback<'a, 'w>(
-    _texture_loader: &'a mut TextureLoader<'a, 'w>,
+    texture_loader: &'a mut TextureLoader<'a, 'w>,
     material_obj: MaterialHandle<'a>,
 ) -> BoxedFuture<'a, anyhow::Result<Option<CelMaterial>>> {
     Box::pin(async move {
-        println!("using fallback for {:?}", material_obj.name());
+        let model = texture_loader.load_context.path();
+        let name = material_obj.name().unwrap_or("");
+        debug!("using fallback for {:?}", name);
+
         let mut mat = CelMaterial::default();
         mat.is_face = true;
         mat.diffuse_only = true;
+        // the report of the failed cel material says why the fallback is used
+        mat.load_report = material_report(model, name).unwrap_or_else(|| MaterialLoadReport {
+            material: name.to_string(),
+            ..default()
+        });
         Ok(Some(mat))
     })
 }
+
+/// Sets the color space of the map textures once both the material and the image are loaded.
+/// Textures cached by the FBX loader are decoded without knowing which map they are used for,
+/// and hot reloaded images come back in the format of the file.
+pub fn fix_color_spaces(
+    mut material_events: EventReader<AssetEvent<CelMaterial>>,
+    mut image_events: EventReader<AssetEvent<Image>>,
+    materials: Res<Assets<CelMaterial>>,
+    mut images: ResMut<Assets<Image>>,
+) {
+    let mut maps = Vec::new();
+    for event in material_events.iter() {
+        let handle = match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } => handle,
+            _ => continue,
+        };
+        guard! { let Some(material) = materials.get(handle) else { continue } }
+        maps.extend(material.maps());
+    }
+
+    for event in image_events.iter() {
+        let handle = match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } => handle,
+            _ => continue,
+        };
+        for (_, material) in materials.iter() {
+            maps.extend(material.maps().into_iter().filter(|(_, image)| *image == handle));
+        }
+    }
+
+    for (map, image) in maps {
+        // only look at the format first, get_mut marks the image as modified
+        let format = images.get(image).map(|i| i.texture_descriptor.format);
+        guard! { let Some(format) = format else { continue } }
+        guard! { let Some(target) = with_color_space(format, map.is_srgb()) else { continue } }
+        if target != format {
+            if let Some(image) = images.get_mut(image) {
+                debug!("{:?} texture format {:?} -> {:?}", map, format, target);
+                image.texture_descriptor.format = target;
+            }
+        }
+    }
+}
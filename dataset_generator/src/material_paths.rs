@@ -0,0 +1,174 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use bevy::asset::LoadContext;
+use bevy::utils::HashMap;
+use lazy_static::lazy_static;
+
+use crate::config::{load_config_asset, read_config_file};
+
+pub const MATERIAL_PATHS_FILENAME: &'static str = "material_paths.ron";
+
+lazy_static! {
+    /// `m_Name` to file of the material JSON files in every searched directory, shared between loads.
+    static ref NAME_INDEX: Mutex<HashMap<PathBuf, Arc<NameIndex>>> = Default::default();
+}
+
+/// Directories searched for the material properties JSON.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct MaterialPaths {
+    /// Relative to the folder of the model, tried in order
+    pub search_paths: Vec<PathBuf>,
+    /// Relative to the assets folder, searched after the model folders
+    pub libraries: Vec<PathBuf>,
+    /// File names of the asset lists exported with the materials, looked for in the same folders.
+    /// They give the names of the textures the material JSON binds by `m_PathID`.
+    #[serde(default)]
+    pub texture_lists: Vec<String>,
+}
+
+impl MaterialPaths {
+    pub fn load_from_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        read_config_file(path)
+    }
+
+    pub async fn load(load_context: &LoadContext<'_>) -> anyhow::Result<Self> {
+        load_config_asset(load_context, MATERIAL_PATHS_FILENAME).await
+    }
+
+    /// Directories to search for the materials of the model, relative to the assets folder.
+    pub fn directories(&self, model: &Path) -> Vec<PathBuf> {
+        let parent = model.parent().unwrap_or(Path::new(""));
+        self.search_paths
+            .iter()
+            .map(|p| parent.join(p))
+            .chain(self.libraries.iter().cloned())
+            .collect()
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct MaterialName {
+    #[serde(rename = "m_Name", default)]
+    m_name: String,
+}
+
+/// Finds the properties file of the material, first by file name and then by the `m_Name` inside the files.
+/// Returns the paths that were tried if there is none.
+pub async fn find_material_json(
+    load_context: &LoadContext<'_>,
+    material_paths: &MaterialPaths,
+    name: &str,
+) -> Result<PathBuf, Vec<String>> {
+    let directories = material_paths.directories(load_context.path());
+
+    let mut tried = Vec::new();
+    for directory in &directories {
+        let path = directory.join(format!("{}.json", name));
+        if load_context.asset_io().is_file(&path) {
+            return Ok(path);
+        }
+        tried.push(path.display().to_string());
+    }
+
+    for directory in &directories {
+        if !load_context.asset_io().is_dir(directory) {
+            continue;
+        }
+        let index = name_index(load_context, directory).await;
+        if let Some(path) = index.names.get(name) {
+            return Ok(path.clone());
+        }
+        tried.push(format!("{}/*.json with m_Name {:?}", directory.display(), name));
+    }
+
+    Err(tried)
+}
+
+struct NameIndex {
+    /// JSON files of the directory when the index was built
+    files: Vec<PathBuf>,
+    names: HashMap<String, PathBuf>,
+}
+
+/// Index of the directory, built again when files were added or removed since the last model was loaded.
+async fn name_index(load_context: &LoadContext<'_>, directory: &Path) -> Arc<NameIndex> {
+    let mut files = match load_context.asset_io().read_directory(directory) {
+        Ok(files) => files
+            .filter(|file| file.extension().map(|e| e == "json").unwrap_or(false))
+            .collect::<Vec<_>>(),
+        Err(_) => Vec::new(),
+    };
+    files.sort();
+
+    if let Some(index) = NAME_INDEX.lock().unwrap().get(directory) {
+        if index.files == files {
+            return index.clone();
+        }
+    }
+
+    let mut names = HashMap::default();
+    for file in &files {
+        guard! { let Ok(bytes) = load_context.read_asset_bytes(file).await else { continue } }
+        // files that are not material properties have no name and are left out
+        if let Ok(material) = serde_json::from_slice::<MaterialName>(&bytes) {
+            if !material.m_name.is_empty() {
+                names.insert(material.m_name, file.clone());
+            }
+        }
+    }
+
+    let index = Arc::new(NameIndex { files, names });
+    NAME_INDEX
+        .lock()
+        .unwrap()
+        .insert(directory.to_path_buf(), index.clone());
+    index
+}
+
+/// Entry of an exported asset list, a JSON array of every exported asset.
+#[derive(serde::Deserialize)]
+struct ExportedAsset {
+    #[serde(rename = "Name")]
+    name: String,
+    #[serde(rename = "PathID")]
+    path_id: i64,
+    /// Lists without types are assumed to only contain textures
+    #[serde(rename = "Type", default)]
+    kind: Option<String>,
+}
+
+/// `m_PathID` to name of the textures in the asset lists of the material folders of the model.
+/// Lists that cannot be read are returned with their error and left out.
+pub async fn load_texture_index(
+    load_context: &LoadContext<'_>,
+    material_paths: &MaterialPaths,
+) -> (HashMap<i64, String>, Vec<(String, String)>) {
+    let mut index = HashMap::default();
+    let mut errors = Vec::new();
+    for directory in material_paths.directories(load_context.path()) {
+        for file_name in &material_paths.texture_lists {
+            let path = directory.join(file_name);
+            if !load_context.asset_io().is_file(&path) {
+                continue;
+            }
+            let assets = match load_context.read_asset_bytes(&path).await {
+                Ok(bytes) => serde_json::from_slice::<Vec<ExportedAsset>>(&bytes).map_err(|e| e.to_string()),
+                Err(e) => Err(e.to_string()),
+            };
+            match assets {
+                Ok(assets) => {
+                    let textures = assets
+                        .into_iter()
+                        .filter(|a| a.kind.as_deref().map(|k| k == "Texture2D").unwrap_or(true));
+                    for asset in textures {
+                        // the folders closest to the model come first and win
+                        index.entry(asset.path_id).or_insert(asset.name);
+                    }
+                }
+                Err(error) => errors.push((path.display().to_string(), error)),
+            }
+        }
+    }
+    (index, errors)
+}
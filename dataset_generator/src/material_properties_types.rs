@@ -1,4 +1,5 @@
-use serde::Deserialize;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
 use serde::Serialize;
 use serde_json::Value;
 
@@ -29,8 +30,8 @@ pub struct MShader {
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct MSavedProperties {
-    //#[serde(rename = "m_TexEnvs", default)]
-    //pub m_tex_envs: MTexEnvs,
+    #[serde(rename = "m_TexEnvs", default, deserialize_with = "deserialize_tex_envs")]
+    pub m_tex_envs: MTexEnvs,
     #[serde(rename = "m_Ints", default)]
     pub m_ints: Value,
     #[serde(rename = "m_Floats", default)]
@@ -39,6 +40,34 @@ pub struct MSavedProperties {
     pub m_colors: MColors,
 }
 
+/// Dumps write `m_TexEnvs` either as an object keyed by the property name
+/// or as a list of `Key`/`Value` pairs, like Unity serializes it.
+fn deserialize_tex_envs<'de, D: Deserializer<'de>>(deserializer: D) -> Result<MTexEnvs, D::Error> {
+    #[derive(Deserialize)]
+    struct Entry {
+        #[serde(rename = "Key", alias = "first")]
+        key: String,
+        #[serde(rename = "Value", alias = "second")]
+        value: Value,
+    }
+
+    // the list goes first, a struct would also accept a sequence of its fields
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Shape {
+        List(Vec<Entry>),
+        Map(MTexEnvs),
+    }
+
+    match Shape::deserialize(deserializer)? {
+        Shape::List(entries) => {
+            let map = entries.into_iter().map(|e| (e.key, e.value)).collect();
+            serde_json::from_value(Value::Object(map)).map_err(D::Error::custom)
+        }
+        Shape::Map(tex_envs) => Ok(tex_envs),
+    }
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct MTexEnvs {
@@ -46,6 +75,8 @@ pub struct MTexEnvs {
     pub anim_texture_q: Texture,
     #[serde(rename = "_AnimTextureT", default)]
     pub anim_texture_t: Texture,
+    #[serde(rename = "_BumpMap", default)]
+    pub bump_map: Texture,
     #[serde(rename = "_ClipAlphaTex", default)]
     pub clip_alpha_tex: Texture,
     #[serde(rename = "_FaceMapTex", default)]
@@ -75,6 +106,24 @@ pub struct Texture {
     pub m_offset: Vec2,
 }
 
+impl Texture {
+    pub fn path_id(&self) -> Option<i64> {
+        if self.m_texture.is_null || self.m_texture.m_path_id == 0 {
+            None
+        } else {
+            Some(self.m_texture.m_path_id)
+        }
+    }
+
+    /// Tiling and offset packed like Unity's `_ST` vectors, identity when the texture is not set.
+    pub fn scale_offset(&self) -> [f32; 4] {
+        if self.path_id().is_none() || (self.m_scale.x == 0.0 && self.m_scale.y == 0.0) {
+            return [1.0, 1.0, 0.0, 0.0];
+        }
+        [self.m_scale.x, self.m_scale.y, self.m_offset.x, self.m_offset.y]
+    }
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct TextureFile {
@@ -192,14 +241,21 @@ pub struct MFloats {
     pub outline_polygon_offset_factor: f32,
     #[serde(rename = "_OutlinePolygonOffsetUnit", default)]
     pub outline_polygon_offset_unit: f32,
+    /// 0 turns the outline off, materials without it keep theirs
     #[serde(rename = "_OutlineType", default)]
-    pub outline_type: f32,
+    pub outline_type: Option<f32>,
     #[serde(rename = "_OutlineWidth", default)]
     pub outline_width: f32,
     #[serde(rename = "_PolygonOffsetFactor", default)]
     pub polygon_offset_factor: f32,
     #[serde(rename = "_PolygonOffsetUnit", default)]
     pub polygon_offset_unit: f32,
+    #[serde(rename = "_RimLightIntensity", default)]
+    pub rim_light_intensity: Option<f32>,
+    #[serde(rename = "_RimLightThickness", default)]
+    pub rim_light_thickness: Option<f32>,
+    #[serde(rename = "_RimLightType", default)]
+    pub rim_light_type: Option<f32>,
     #[serde(rename = "_Scale", default)]
     pub scale: f32,
     #[serde(rename = "_ShadowRampWidth", default)]
@@ -405,3 +461,24 @@ pub struct Color {
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct MStringTagMap {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tex_envs(json: &str) -> MTexEnvs {
+        let properties: MaterialPropertiesRoot = serde_json::from_str(json).unwrap();
+        properties.m_saved_properties.m_tex_envs
+    }
+
+    #[test]
+    fn tex_envs_map_and_list_are_the_same() {
+        let map = tex_envs(include_str!("../tests/fixtures/tex_envs_map.json"));
+        let list = tex_envs(include_str!("../tests/fixtures/tex_envs_list.json"));
+        assert_eq!(map, list);
+        assert_eq!(map.main_tex.path_id(), Some(-4211915893742440193));
+        assert_eq!(map.main_tex.scale_offset(), [2.0, 1.0, 0.5, 0.0]);
+        assert_eq!(map.light_map_tex.path_id(), Some(7190411305813263121));
+        assert_eq!(map.mtmap.path_id(), None);
+    }
+}
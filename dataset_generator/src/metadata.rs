@@ -0,0 +1,157 @@
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::collections::HashSet;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use bevy::prelude::*;
+
+use crate::lights::LightPreset;
+use crate::output::{OutputLayout, Pass, SampleWriter};
+
+pub const SAMPLES_FILENAME: &'static str = "samples.jsonl";
+
+/// Material parameters that can change between samples.
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct MaterialSettings {
+    pub day_night_cycle: f32,
+    /// Meshes rendered with the fallback material because their cel material could not be loaded
+    pub fallback_materials: usize,
+}
+
+/// Everything needed to reproduce one rendered pair.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct SampleRecord {
+    pub sample: String,
+    pub character: String,
+    pub character_index: usize,
+    pub sample_index: usize,
+    pub seed: u64,
+    pub preset_group: String,
+    pub camera_preset: usize,
+    pub light_preset: usize,
+    pub camera: Transform,
+    pub light: LightPreset,
+    pub materials: MaterialSettings,
+    pub layout: OutputLayout,
+    /// Diffuse only render, relative to the output directory
+    pub input: String,
+    /// Cel shaded render, relative to the output directory. Same file as `input` for the aligned layout.
+    pub target: String,
+    /// Files of the extra passes
+    pub passes: BTreeMap<Pass, String>,
+}
+
+/// Appends one JSON line per sample to `samples.jsonl` in the output directory.
+#[derive(Resource)]
+pub struct SampleLog {
+    path: PathBuf,
+    file: BufWriter<File>,
+    /// Samples already in the log, the record of a sample that is rendered again replaces the old one
+    logged: HashSet<String>,
+    /// Records of samples whose images are still being written
+    waiting: Vec<SampleRecord>,
+    /// Rendered samples that have no record because it or one of their images could not be written
+    missing: usize,
+}
+
+impl SampleLog {
+    pub fn open(output: &Path) -> anyhow::Result<Self> {
+        let path = output.join(SAMPLES_FILENAME);
+        let mut logged = HashSet::new();
+        if path.exists() {
+            for line in BufReader::new(File::open(&path)?).lines() {
+                // the last line can be cut off if the previous run crashed while writing it
+                if let Ok(record) = serde_json::from_str::<SampleRecord>(&line?) {
+                    logged.insert(record.sample);
+                }
+            }
+        }
+
+        Ok(Self {
+            file: open_append(&path)?,
+            path,
+            logged,
+            waiting: Vec::new(),
+            missing: 0,
+        })
+    }
+
+    /// Rendered samples of this run that are not in the log.
+    pub fn missing(&self) -> usize {
+        self.missing
+    }
+
+    /// Whether every queued record has been written or dropped.
+    pub fn is_idle(&self) -> bool {
+        self.waiting.is_empty()
+    }
+
+    /// Adds the record once all images of the sample are written.
+    pub fn queue(&mut self, record: SampleRecord) {
+        self.waiting.push(record);
+    }
+
+    /// Writes the records of samples whose images have been written.
+    /// Samples with missing images get no record, so the log only lists complete pairs.
+    pub fn flush(&mut self, writer: &SampleWriter) {
+        for record in std::mem::take(&mut self.waiting) {
+            match writer.take_result(&record.sample) {
+                None => self.waiting.push(record),
+                Some(true) => self.add(&record),
+                Some(false) => {
+                    error!("Not logging {}, some of its images could not be written", record.sample);
+                    self.missing += 1;
+                }
+            }
+        }
+    }
+
+    /// Writes the record, or reports the error and counts the sample as missing.
+    fn add(&mut self, record: &SampleRecord) {
+        if let Err(e) = self.write(record) {
+            error!("Cannot write the record of {} to {}: {:?}", record.sample, SAMPLES_FILENAME, e);
+            self.missing += 1;
+        }
+    }
+
+    fn write(&mut self, record: &SampleRecord) -> anyhow::Result<()> {
+        // skipped samples are never written, so this one was rendered again, e.g. with another seed
+        if !self.logged.insert(record.sample.clone()) {
+            self.remove(&record.sample)?;
+        }
+        serde_json::to_writer(&mut self.file, record)?;
+        self.file.write_all(b"\n")?;
+        // flush every record so the log is complete if the run crashes
+        self.file.flush()?;
+        Ok(())
+    }
+
+    /// Rewrites the log without the records of the sample.
+    fn remove(&mut self, sample: &str) -> anyhow::Result<()> {
+        self.file.flush()?;
+        let temp = self.path.with_extension("jsonl.tmp");
+        {
+            let mut kept = BufWriter::new(File::create(&temp)?);
+            for line in BufReader::new(File::open(&self.path)?).lines() {
+                let line = line?;
+                let keep = serde_json::from_str::<SampleRecord>(&line)
+                    .map(|record| record.sample != sample)
+                    .unwrap_or(false);
+                if keep {
+                    kept.write_all(line.as_bytes())?;
+                    kept.write_all(b"\n")?;
+                }
+            }
+            kept.flush()?;
+        }
+        std::fs::rename(&temp, &self.path)?;
+        self.file = open_append(&self.path)?;
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> anyhow::Result<BufWriter<File>> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    Ok(BufWriter::new(file))
+}
@@ -0,0 +1,180 @@
+use bevy::{
+    pbr::{MaterialPipeline, MaterialPipelineKey, NotShadowCaster, NotShadowReceiver},
+    prelude::*,
+    reflect::TypeUuid,
+    render::{
+        mesh::MeshVertexBufferLayout,
+        render_resource::{
+            AsBindGroup, Face, RenderPipelineDescriptor, ShaderRef, ShaderType,
+            SpecializedMeshPipelineError,
+        },
+    },
+};
+
+use crate::cel_material::CelMaterial;
+use crate::material_properties_types::{self, MaterialPropertiesRoot};
+
+/// Outline values of the material JSON.
+#[derive(Debug, Clone, ShaderType, Default)]
+pub struct OutlineProperties {
+    /// Per material ID, picked with the lightmap alpha like the shadow ramp rows
+    colors: [Color; 5],
+    width: f32,
+    max_z_offset: f32,
+    /// View depths at which `width_adjust_scales` apply
+    width_adjust_zs: Vec4,
+    width_adjust_scales: Vec4,
+}
+
+impl OutlineProperties {
+    pub fn width(&self) -> f32 {
+        self.width
+    }
+}
+
+impl From<&MaterialPropertiesRoot> for OutlineProperties {
+    fn from(properties: &MaterialPropertiesRoot) -> Self {
+        let floats = &properties.m_saved_properties.m_floats;
+        let colors = &properties.m_saved_properties.m_colors;
+        let vec = |c: &material_properties_types::Color| Vec4::new(c.r, c.g, c.b, c.a);
+        OutlineProperties {
+            colors: [
+                colors.outline_color.clone().into(),
+                colors.outline_color2.clone().into(),
+                colors.outline_color3.clone().into(),
+                colors.outline_color4.clone().into(),
+                colors.outline_color5.clone().into(),
+            ],
+            width: floats.outline_width,
+            max_z_offset: floats.max_outline_zoffset,
+            width_adjust_zs: vec(&colors.outline_width_adjust_zs),
+            width_adjust_scales: vec(&colors.outline_width_adjust_scales),
+        }
+    }
+}
+
+/// Inverted hull drawn behind a cel shaded mesh: the mesh extruded along its normals with front faces culled.
+#[derive(AsBindGroup, TypeUuid, Debug, Clone)]
+#[uuid = "5b7f0d4e-2f4c-4a8e-b1f6-3c2d9e8a7f10"]
+#[bind_group_data(OutlineMaterialKey)]
+pub struct OutlineMaterial {
+    pub is_face: bool,
+    #[texture(0)]
+    #[sampler(1)]
+    light_map: Option<Handle<Image>>,
+    #[uniform(2)]
+    properties: OutlineProperties,
+}
+
+impl OutlineMaterial {
+    pub fn new(is_face: bool, light_map: Option<Handle<Image>>, properties: OutlineProperties) -> Self {
+        Self {
+            is_face,
+            light_map,
+            properties,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct OutlineMaterialKey {
+    is_face: bool,
+}
+
+impl From<&OutlineMaterial> for OutlineMaterialKey {
+    fn from(material: &OutlineMaterial) -> Self {
+        OutlineMaterialKey {
+            is_face: material.is_face,
+        }
+    }
+}
+
+impl Material for OutlineMaterial {
+    fn fragment_shader() -> ShaderRef {
+        "shaders/outline.wgsl".into()
+    }
+
+    fn vertex_shader() -> ShaderRef {
+        "shaders/outline.wgsl".into()
+    }
+
+    fn specialize(
+        _pipeline: &MaterialPipeline<Self>,
+        descriptor: &mut RenderPipelineDescriptor,
+        layout: &MeshVertexBufferLayout,
+        key: MaterialPipelineKey<Self>,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        let mut vertex_attributes = vec![
+            Mesh::ATTRIBUTE_POSITION.at_shader_location(0),
+            Mesh::ATTRIBUTE_NORMAL.at_shader_location(1),
+            Mesh::ATTRIBUTE_UV_0.at_shader_location(2),
+        ];
+        // the vertex color alpha scales the width, VERTEX_COLORS is set by the mesh pipeline
+        if layout.contains(Mesh::ATTRIBUTE_COLOR) {
+            vertex_attributes.push(Mesh::ATTRIBUTE_COLOR.at_shader_location(3));
+        }
+        descriptor.vertex.buffers = vec![layout.get_layout(&vertex_attributes)?];
+
+        if key.bind_group_data.is_face {
+            descriptor.vertex.shader_defs.push("FACE".into());
+            if let Some(fragment) = descriptor.fragment.as_mut() {
+                fragment.shader_defs.push("FACE".into());
+            }
+        }
+
+        descriptor.primitive.cull_mode = Some(Face::Front);
+
+        Ok(())
+    }
+}
+
+/// Marks the outline entity spawned under a cel shaded mesh.
+#[derive(Component)]
+pub struct Outline;
+
+/// Cel shaded meshes that already have been given an outline, or do not need one.
+#[derive(Component)]
+struct OutlineSpawned;
+
+pub struct OutlinePlugin;
+
+impl Plugin for OutlinePlugin {
+    fn build(&self, app: &mut App) {
+        // the hull must not occlude the rim light of the mesh it surrounds
+        app.add_plugin(MaterialPlugin::<OutlineMaterial> {
+            prepass_enabled: false,
+            ..default()
+        })
+            .add_system(spawn_outlines);
+    }
+}
+
+fn spawn_outlines(
+    mut commands: Commands,
+    meshes: Query<(Entity, &Handle<Mesh>, &Handle<CelMaterial>), Without<OutlineSpawned>>,
+    cel_materials: Res<Assets<CelMaterial>>,
+    mut outline_materials: ResMut<Assets<OutlineMaterial>>,
+) {
+    for (entity, mesh, material) in meshes.iter() {
+        // wait for the material, it decides whether the mesh has an outline
+        guard! { let Some(material) = cel_materials.get(material) else { continue } }
+        commands.entity(entity).insert(OutlineSpawned);
+
+        guard! { let Some(outline) = material.outline_material() else { continue } }
+        let outline = commands
+            .spawn((
+                MaterialMeshBundle {
+                    mesh: mesh.clone(),
+                    material: outline_materials.add(outline),
+                    ..default()
+                },
+                Outline,
+                Name::new("Outline"),
+                // the hull would shadow the mesh it surrounds
+                NotShadowCaster,
+                NotShadowReceiver,
+            ))
+            .id();
+        commands.entity(entity).add_child(outline);
+    }
+}
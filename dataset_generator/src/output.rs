@@ -0,0 +1,402 @@
+use std::path::{Path, PathBuf};
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+
+use bevy::prelude::*;
+use bevy::tasks::IoTaskPool;
+use bevy::utils::HashMap;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageBuffer, ImageFormat, Luma, Rgb, Rgb32FImage, RgbImage};
+
+use crate::cel_material::GBuffer;
+
+/// Background of the data passes, zero in every packed value.
+pub const DATA_PASS_CLEAR_COLOR: Color = Color::NONE;
+
+/// How rendered pairs are laid out in the output directory.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputLayout {
+    /// `input/{sample}.png` and `target/{sample}.png`
+    #[default]
+    Split,
+    /// `{sample}.png` with the input on the left and the target on the right, as pix2pix `aligned` mode expects
+    Aligned,
+}
+
+/// Filter used to bring supersampled renders down to the output resolution, all of them work in linear light.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DownscaleFilter {
+    /// Average of every block of pixels
+    #[default]
+    Box,
+    Triangle,
+    Lanczos,
+}
+
+/// Image written for every sample.
+#[derive(
+    clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum Pass {
+    /// Diffuse only render
+    Input,
+    /// Cel shaded render
+    Target,
+    WorldNormal,
+    ViewNormal,
+    /// Linear depth as a 16 bit grayscale image, 1 per 65535th of 10 meters, 0 where there is no surface
+    Depth,
+    /// Lightmap material ID, 0.2 per ID
+    MaterialId,
+    /// White on face materials
+    FaceMask,
+    /// Half lambert light direction term
+    LightTerm,
+    /// Emission strength of the diffuse alpha masked parts, 1 at a strength of 4
+    Emission,
+    /// Mesh UV wrapped to 0-1 in the red and green channels of a 16 bit image, packed with 12 bits per coordinate
+    Uv,
+}
+
+impl Pass {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Pass::Input => "input",
+            Pass::Target => "target",
+            Pass::WorldNormal => "world-normal",
+            Pass::ViewNormal => "view-normal",
+            Pass::Depth => "depth",
+            Pass::MaterialId => "material-id",
+            Pass::FaceMask => "face-mask",
+            Pass::LightTerm => "light-term",
+            Pass::Emission => "emission",
+            Pass::Uv => "uv",
+        }
+    }
+
+    /// Material output used to render this pass.
+    pub fn gbuffer(&self) -> Option<GBuffer> {
+        match self {
+            Pass::Input | Pass::Target => None,
+            Pass::WorldNormal => Some(GBuffer::WorldNormal),
+            Pass::ViewNormal => Some(GBuffer::ViewNormal),
+            Pass::Depth => Some(GBuffer::Depth),
+            Pass::MaterialId => Some(GBuffer::MaterialId),
+            Pass::FaceMask => Some(GBuffer::FaceMask),
+            Pass::LightTerm => Some(GBuffer::LightTerm),
+            Pass::Emission => Some(GBuffer::Emission),
+            Pass::Uv => Some(GBuffer::Uv),
+        }
+    }
+}
+
+/// Writes captured images to the output directory following the selected layout.
+#[derive(Resource, Clone)]
+pub struct SampleWriter {
+    pub layout: OutputLayout,
+    pub output: PathBuf,
+    /// Size of the written images, captures larger than this are downscaled
+    pub resolution: u32,
+    pub filter: DownscaleFilter,
+    /// Halves of aligned pairs waiting for the other image
+    pending: Arc<Mutex<HashMap<String, (Pass, RgbImage)>>>,
+    /// Images of the samples that are being captured or saved
+    samples: Arc<Mutex<HashMap<String, SampleImages>>>,
+}
+
+#[derive(Default)]
+struct SampleImages {
+    /// Captures requested and not written yet
+    unsaved: usize,
+    /// Whether an image could not be written
+    failed: bool,
+}
+
+impl SampleWriter {
+    pub fn new(
+        layout: OutputLayout,
+        output: PathBuf,
+        resolution: u32,
+        filter: DownscaleFilter,
+    ) -> Self {
+        Self {
+            layout,
+            output,
+            resolution,
+            filter,
+            pending: Default::default(),
+            samples: Default::default(),
+        }
+    }
+
+    /// Whether every requested image has been written or has failed.
+    pub fn is_idle(&self) -> bool {
+        self.samples.lock().unwrap().values().all(|images| images.unsaved == 0)
+    }
+
+    /// Announces an image of the sample, to be handed to `submit` once it is captured.
+    pub fn request(&self, sample: &str) {
+        let mut samples = self.samples.lock().unwrap();
+        samples.entry(sample.to_string()).or_default().unsaved += 1;
+    }
+
+    /// Whether all requested images of the sample were written, `None` while some are still in progress.
+    /// The sample is forgotten once it is finished.
+    pub fn take_result(&self, sample: &str) -> Option<bool> {
+        let mut samples = self.samples.lock().unwrap();
+        match samples.get(sample) {
+            Some(images) if images.unsaved > 0 => None,
+            Some(_) => samples.remove(sample).map(|images| !images.failed),
+            None => Some(true),
+        }
+    }
+
+    /// Reports an image of the sample that cannot be written.
+    pub fn fail(&self, sample: &str, pass: Pass, error: impl Debug) {
+        error!("Cannot write {} {}: {:?}", sample, pass.name(), error);
+        // the other half of an aligned pair is useless now
+        self.pending.lock().unwrap().remove(sample);
+        self.finish(sample, false);
+    }
+
+    fn finish(&self, sample: &str, written: bool) {
+        let mut samples = self.samples.lock().unwrap();
+        let images = samples.entry(sample.to_string()).or_default();
+        images.unsaved = images.unsaved.saturating_sub(1);
+        images.failed |= !written;
+    }
+
+    /// Path of the image relative to the output directory.
+    pub fn file_name(&self, sample: &str, pass: Pass) -> String {
+        match (self.layout, pass) {
+            (OutputLayout::Aligned, Pass::Input | Pass::Target) => format!("{}.png", sample),
+            _ => format!("{}/{}.png", pass.name(), sample),
+        }
+    }
+
+    /// Whether every image of the sample is already in the output directory.
+    pub fn exists(&self, sample: &str, passes: &[Pass]) -> bool {
+        passes
+            .iter()
+            .all(|pass| self.output.join(self.file_name(sample, *pass)).is_file())
+    }
+
+    /// Saves a captured image, combining it with the other half of the pair for the aligned layout.
+    pub fn submit(&self, sample: &str, pass: Pass, image: Image) {
+        let image = match image.try_into_dynamic() {
+            Ok(image) => image.to_rgb8(),
+            Err(e) => {
+                self.fail(sample, pass, e);
+                return;
+            }
+        };
+        // all passes keep the same pixel grid, but only colors are filtered: averaging data such as
+        // material IDs or normals makes up values that are not on the mesh
+        let image = if pass.gbuffer().is_some() {
+            decimate(&image, self.resolution)
+        } else {
+            downscale(image, self.resolution, self.filter)
+        };
+
+        let image = match pass {
+            Pass::Depth => DynamicImage::ImageLuma16(unpack_depth(&image)),
+            Pass::Uv => DynamicImage::ImageRgb16(unpack_uv(&image)),
+            _ => DynamicImage::ImageRgb8(image),
+        };
+
+        let image = match (self.layout, pass, image) {
+            (OutputLayout::Aligned, Pass::Input | Pass::Target, DynamicImage::ImageRgb8(image)) => {
+                let other = {
+                    let mut pending = self.pending.lock().unwrap();
+                    match pending.remove(sample) {
+                        Some((_, other)) => other,
+                        None => {
+                            pending.insert(sample.to_string(), (pass, image));
+                            // written together with the other half
+                            self.finish(sample, true);
+                            return;
+                        }
+                    }
+                };
+
+                if pass == Pass::Input {
+                    DynamicImage::ImageRgb8(side_by_side(&image, &other))
+                } else {
+                    DynamicImage::ImageRgb8(side_by_side(&other, &image))
+                }
+            }
+            (_, _, image) => image,
+        };
+
+        let path = self.output.join(self.file_name(sample, pass));
+        let writer = self.clone();
+        let sample = sample.to_string();
+        IoTaskPool::get()
+            .spawn(async move {
+                match save(&image, &path) {
+                    Ok(()) => writer.finish(&sample, true),
+                    Err(e) => writer.fail(&sample, pass, e),
+                }
+            })
+            .detach();
+    }
+}
+
+/// Writes next to the destination and renames, so an interrupted run never leaves a truncated image
+/// that `exists` would take for a finished one.
+fn save(image: &DynamicImage, path: &Path) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let temp = path.with_extension("png.tmp");
+    image.save_with_format(&temp, ImageFormat::Png)?;
+    std::fs::rename(&temp, path)?;
+    Ok(())
+}
+
+/// The shader splits the 16 bit depth over the red (high byte) and green (low byte) channels.
+fn unpack_depth(image: &RgbImage) -> ImageBuffer<Luma<u16>, Vec<u16>> {
+    ImageBuffer::from_fn(image.width(), image.height(), |x, y| {
+        let pixel = image.get_pixel(x, y);
+        Luma([(pixel[0] as u16) << 8 | pixel[1] as u16])
+    })
+}
+
+/// The shader packs 12 bits of U and V into the 24 bits of the pixel, U first.
+fn unpack_uv(image: &RgbImage) -> ImageBuffer<Rgb<u16>, Vec<u16>> {
+    let widen = |value: u16| (value as u32 * 65535 / 4095) as u16;
+    ImageBuffer::from_fn(image.width(), image.height(), |x, y| {
+        let [r, g, b] = image.get_pixel(x, y).0.map(|c| c as u16);
+        let u = r << 4 | g >> 4;
+        let v = (g & 0xf) << 8 | b;
+        Rgb([widen(u), widen(v), 0])
+    })
+}
+
+fn side_by_side(a: &RgbImage, b: &RgbImage) -> RgbImage {
+    let mut result = RgbImage::new(a.width() + b.width(), a.height().max(b.height()));
+    image::imageops::replace(&mut result, a, 0, 0);
+    image::imageops::replace(&mut result, b, a.width() as i64, 0);
+    result
+}
+
+/// Filters in linear light for every filter, the captures are sRGB encoded and averaging the encoded values
+/// would darken edges and highlights.
+fn downscale(image: RgbImage, resolution: u32, filter: DownscaleFilter) -> RgbImage {
+    if image.width() <= resolution && image.height() <= resolution {
+        return image;
+    }
+
+    let to_linear: Vec<f32> = (0..=255u8).map(|v| srgb_to_linear(v as f32 / 255.0)).collect();
+    let linear = Rgb32FImage::from_fn(image.width(), image.height(), |x, y| {
+        Rgb(image.get_pixel(x, y).0.map(|c| to_linear[c as usize]))
+    });
+
+    let small = match filter {
+        DownscaleFilter::Box => box_downscale(&linear, image.width() / resolution),
+        DownscaleFilter::Triangle => {
+            image::imageops::resize(&linear, resolution, resolution, FilterType::Triangle)
+        }
+        DownscaleFilter::Lanczos => {
+            image::imageops::resize(&linear, resolution, resolution, FilterType::Lanczos3)
+        }
+    };
+
+    RgbImage::from_fn(small.width(), small.height(), |x, y| {
+        // lanczos over- and undershoots at edges
+        Rgb(small.get_pixel(x, y).0.map(|c| (linear_to_srgb(c.clamp(0.0, 1.0)) * 255.0).round() as u8))
+    })
+}
+
+/// Averages every block of pixels.
+fn box_downscale(image: &Rgb32FImage, factor: u32) -> Rgb32FImage {
+    let factor = factor.max(1);
+    let area = (factor * factor) as f32;
+    Rgb32FImage::from_fn(image.width() / factor, image.height() / factor, |x, y| {
+        let mut sum = [0f32; 3];
+        for dy in 0..factor {
+            for dx in 0..factor {
+                let pixel = image.get_pixel(x * factor + dx, y * factor + dy);
+                for c in 0..3 {
+                    sum[c] += pixel[c];
+                }
+            }
+        }
+        Rgb(sum.map(|s| s / area))
+    })
+}
+
+fn srgb_to_linear(v: f32) -> f32 {
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(v: f32) -> f32 {
+    if v <= 0.0031308 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Keeps the pixel at the centre of every block, so data passes only contain values that were rendered.
+fn decimate<P: image::Pixel>(image: &ImageBuffer<P, Vec<P::Subpixel>>, resolution: u32) -> ImageBuffer<P, Vec<P::Subpixel>> {
+    if image.width() <= resolution && image.height() <= resolution {
+        return image.clone();
+    }
+    let (width, height) = (resolution.min(image.width()), resolution.min(image.height()));
+    ImageBuffer::from_fn(width, height, |x, y| {
+        let source_x = ((x as u64 * 2 + 1) * image.width() as u64 / (width as u64 * 2)) as u32;
+        let source_y = ((y as u64 * 2 + 1) * image.height() as u64 / (height as u64 * 2)) as u32;
+        *image.get_pixel(source_x, source_y)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decimate_keeps_rendered_values() {
+        // alternating material IDs 2 and 4, averaging would create 3
+        let image = RgbImage::from_fn(8, 8, |x, _| Rgb([if x % 2 == 0 { 102 } else { 204 }; 3]));
+        let small = decimate(&image, 4);
+        assert_eq!(small.dimensions(), (4, 4));
+        assert!(small.pixels().all(|p| p[0] == 102 || p[0] == 204));
+    }
+
+    #[test]
+    fn unpack_packed_passes() {
+        let depth = RgbImage::from_pixel(1, 1, Rgb([0x12, 0x34, 0]));
+        assert_eq!(unpack_depth(&depth).get_pixel(0, 0)[0], 0x1234);
+
+        // U 0xfff and V 0x001
+        let uv = RgbImage::from_pixel(1, 1, Rgb([0xff, 0xf0, 0x01]));
+        let uv = unpack_uv(&uv);
+        assert_eq!(uv.get_pixel(0, 0).0, [65535, 16, 0]);
+    }
+
+    #[test]
+    fn cleared_background_unpacks_to_zero() {
+        let [r, g, b, _] = DATA_PASS_CLEAR_COLOR.as_rgba_u8();
+        let cleared = RgbImage::from_pixel(1, 1, Rgb([r, g, b]));
+        assert_eq!(unpack_depth(&cleared).get_pixel(0, 0)[0], 0);
+        assert_eq!(unpack_uv(&cleared).get_pixel(0, 0).0, [0, 0, 0]);
+    }
+
+    #[test]
+    fn downscale_averages_in_linear_light() {
+        let image = RgbImage::from_fn(2, 2, |x, _| Rgb([if x == 0 { 0 } else { 255 }; 3]));
+        for filter in [DownscaleFilter::Box, DownscaleFilter::Triangle, DownscaleFilter::Lanczos] {
+            let small = downscale(image.clone(), 1, filter);
+            // half the light is 188 in sRGB, not 128
+            let value = small.get_pixel(0, 0)[0];
+            assert!((187..=189).contains(&value), "{:?} gave {}", filter, value);
+        }
+    }
+}
@@ -0,0 +1,186 @@
+#define_import_path cel::material
+
+// Material bindings of the cel shader, shared with its prepass
+
+struct Direction {
+    forward: vec3<f32>,
+    right: vec3<f32>,
+}
+
+struct ShadowRamp {
+    width: f32,
+    day_mult_colors: array<vec4<f32>, 5>,
+    night_mult_colors: array<vec4<f32>, 5>,
+    transition_range1: f32,
+    transition_range2: f32,
+    transition_range3: f32,
+    transition_range4: f32,
+    transition_range5: f32,
+    transition_softness1: f32,
+    transition_softness2: f32,
+    transition_softness3: f32,
+    transition_softness4: f32,
+    transition_softness5: f32,
+}
+
+struct GlobalSpecular {
+    shininess1: f32,
+    shininess2: f32,
+    shininess3: f32,
+    shininess4: f32,
+    shininess5: f32,
+    specular_multi1: f32,
+    specular_multi2: f32,
+    specular_multi3: f32,
+    specular_multi4: f32,
+    specular_multi5: f32,
+}
+
+// position with w 1 for point lights, direction to the light with w 0 for directional lights
+struct CelLights {
+    main_position: vec4<f32>,
+    main_color: vec4<f32>,
+    fill_position: vec4<f32>,
+    fill_color: vec4<f32>,
+}
+
+struct MaterialProperties {
+    head_direction: Direction,
+    lights: CelLights,
+    shadow_ramp_values: ShadowRamp,
+    global_specular: GlobalSpecular,
+
+    use_materials: vec4<f32>, //1 1 1 1 1
+    metal_map_light_color: vec4<f32>,
+    metal_map_dark_color: vec4<f32>,
+    metal_map_shadow_multi_color: vec4<f32>,
+    metal_map_sharp_layer_color: vec4<f32>,
+    metal_map_specular_color: vec4<f32>,
+    specular_color: vec4<f32>,
+    hit_color: vec4<f32>,
+
+    // tiling in xy, offset in zw
+    main_tex_st: vec4<f32>,
+    light_map_tex_st: vec4<f32>,
+    face_map_tex_st: vec4<f32>,
+    normal_map_tex_st: vec4<f32>,
+    metal_map_tex_st: vec4<f32>,
+    shadow_ramp_tex_st: vec4<f32>,
+
+    day_night_cycle: f32,
+
+    use_shadow_ramp_texture: f32,
+
+    light_area: f32, //0.55
+    flip_light_map: f32, //0
+    face_map_softness: f32, //0.1
+
+    use_ligth_map_color_ao: f32, //1
+    use_vertex_color_ao: f32, //1
+
+    normal_map_scale: f32, //0.2
+
+    use_normal_map: f32, //1
+
+    use_back_space_uv: f32, //1
+
+    use_metal_map: f32,
+    metal_map_tile_scale: f32,
+    metal_map_brightness: f32,
+
+    metal_map_shininess: f32,
+    metal_map_sharp_layer_offset: f32,
+    
+    metal_map_specular_atten_in_shadow: f32,
+    metal_map_specular_scale: f32,
+
+    use_fresnel: f32,
+    hit_color_fresnel_power: f32,
+    hit_color_scaler: f32,
+
+    rim_light_type: f32,
+    rim_light_intensity: f32,
+    rim_light_thickness: f32,
+
+    alpha_cutoff: f32,
+    dither_alpha: f32,
+
+    use_emission: f32,
+    emission_scaler: f32,
+    emission_scaler1: f32,
+    emission_scaler2: f32,
+    emission_scaler3: f32,
+    emission_scaler4: f32,
+    emission_scaler5: f32,
+    emission_strength_lerp: f32,
+}
+
+@group(1) @binding(0)
+var diffuse_tex: texture_2d<f32>;
+@group(1) @binding(1)
+var diffuse_sampler: sampler;
+
+@group(1) @binding(2)
+var face_light_map_tex: texture_2d<f32>;
+@group(1) @binding(3)
+var face_light_map_sampler: sampler;
+
+@group(1) @binding(4)
+var light_map_tex: texture_2d<f32>;
+@group(1) @binding(5)
+var light_map_sampler: sampler;
+
+@group(1) @binding(6)
+var shadow_ramp_tex: texture_2d<f32>;
+@group(1) @binding(7)
+var shadow_ramp_sampler: sampler;
+
+@group(1) @binding(8)
+var normal_map_tex: texture_2d<f32>;
+@group(1) @binding(9)
+var normal_map_sampler: sampler;
+
+@group(1) @binding(10)
+var metal_map_tex: texture_2d<f32>;
+@group(1) @binding(11)
+var metal_map_sampler: sampler;
+
+@group(1) @binding(12)
+var<uniform> properties: MaterialProperties;
+
+// 4x4 ordered dither thresholds
+fn dither_threshold(frag_coord: vec4<f32>) -> f32 {
+    // a var, naga only indexes constant arrays with constants
+    var bayer = array<f32, 16>(
+        0.0, 8.0, 2.0, 10.0,
+        12.0, 4.0, 14.0, 6.0,
+        3.0, 11.0, 1.0, 9.0,
+        15.0, 7.0, 13.0, 5.0,
+    );
+    let x = u32(frag_coord.x) % 4u;
+    let y = u32(frag_coord.y) % 4u;
+    return (bayer[y * 4u + x] + 0.5) / 16.0;
+}
+
+// Whether the fragment is cut out by the alpha mode of the material
+fn alpha_clipped(alpha: f32, frag_coord: vec4<f32>) -> bool {
+    var clipped = false;
+#ifdef ALPHA_MASK
+    clipped = alpha < properties.alpha_cutoff;
+#endif
+#ifdef ALPHA_DITHER
+    clipped = alpha * properties.dither_alpha < dither_threshold(frag_coord);
+#endif
+#ifdef ALPHA_BLEND
+    clipped = alpha <= 0.0;
+#ifdef GBUFFER
+    // the data passes cannot blend, they keep the mostly opaque parts
+    clipped = alpha < 0.5;
+#endif
+#endif
+    return clipped;
+}
+
+fn transform_uv(uv: vec2<f32>, st: vec4<f32>) -> vec2<f32> {
+    return uv * st.xy + st.zw;
+}
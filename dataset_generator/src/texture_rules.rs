@@ -0,0 +1,114 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::bail;
+use bevy::asset::LoadContext;
+
+use crate::config::{load_config_asset, read_config_file};
+use crate::material_loader::MapKind;
+
+pub const TEXTURE_RULES_FILENAME: &'static str = "texture_rules.ron";
+
+/// Texture name patterns of every map, tried in order until a texture is found.
+///
+/// Patterns can contain these placeholders:
+/// - `{prefix:N}` the first N underscore separated tokens of the diffuse texture name,
+///   or all but the last -N tokens when N is negative
+/// - `{diffuse}` the diffuse texture name
+/// - `{material}` the material name without its extension
+/// - `{tex_env}` the name of the texture the material JSON binds to the map, looked up by its `m_PathID`
+///   in the exported texture lists, patterns using it are skipped when there is none
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct TextureRules {
+    /// Diffuse texture names containing this use the face rules
+    pub face_marker: String,
+    pub body: BTreeMap<MapKind, Vec<String>>,
+    pub face: BTreeMap<MapKind, Vec<String>>,
+}
+
+impl TextureRules {
+    pub fn load_from_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let rules: Self = read_config_file(path)?;
+        rules.validate()?;
+        Ok(rules)
+    }
+
+    pub async fn load(load_context: &LoadContext<'_>) -> anyhow::Result<Self> {
+        let rules: Self = load_config_asset(load_context, TEXTURE_RULES_FILENAME).await?;
+        rules.validate()?;
+        Ok(rules)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        // with enough tokens every valid placeholder expands
+        let names = TextureNames {
+            tokens: vec!["x"; 64],
+            material: "x",
+            is_face: false,
+        };
+        for pattern in self.body.values().chain(self.face.values()).flatten() {
+            if names.expand(pattern, Some("x")).is_none() {
+                bail!("Invalid texture name pattern {:?}", pattern);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn patterns(&self, is_face: bool, map: MapKind) -> &[String] {
+        let rules = if is_face { &self.face } else { &self.body };
+        rules.get(&map).map(|p| p.as_slice()).unwrap_or(&[])
+    }
+}
+
+/// Values the patterns of one material are expanded with.
+pub struct TextureNames<'a> {
+    tokens: Vec<&'a str>,
+    material: &'a str,
+    pub is_face: bool,
+}
+
+impl<'a> TextureNames<'a> {
+    pub fn new(diffuse: &'a str, material: &'a str, rules: &TextureRules) -> Self {
+        Self {
+            tokens: diffuse.split('_').collect(),
+            material,
+            is_face: diffuse.contains(&rules.face_marker),
+        }
+    }
+
+    /// Texture name for the pattern, `None` if the diffuse name has too few tokens
+    /// or the pattern needs the texture bound in the material JSON and there is none.
+    pub fn expand(&self, pattern: &str, tex_env: Option<&str>) -> Option<String> {
+        let mut result = String::new();
+        let mut rest = pattern;
+        while let Some(start) = rest.find('{') {
+            result.push_str(&rest[..start]);
+            let end = start + rest[start..].find('}')?;
+            result.push_str(&self.placeholder(&rest[start + 1..end], tex_env)?);
+            rest = &rest[end + 1..];
+        }
+        result.push_str(rest);
+        Some(result)
+    }
+
+    fn placeholder(&self, name: &str, tex_env: Option<&str>) -> Option<String> {
+        match name.split_once(':') {
+            None if name == "tex_env" => tex_env.map(|name| name.to_string()),
+            None if name == "diffuse" => Some(self.tokens.join("_")),
+            None if name == "material" => Some(self.material.to_string()),
+            Some(("prefix", count)) => {
+                let count: i32 = count.parse().ok()?;
+                let len = if count < 0 {
+                    self.tokens.len().checked_sub(count.unsigned_abs() as usize)?
+                } else {
+                    count as usize
+                };
+                if len == 0 || len > self.tokens.len() {
+                    return None;
+                }
+                Some(self.tokens[..len].join("_"))
+            }
+            _ => None,
+        }
+    }
+}